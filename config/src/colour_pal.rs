@@ -2,6 +2,14 @@ use std::collections::HashMap;
 
 pub type Palette = HashMap<String, String>;
 
+/// Palette keys of the 16 ANSI colours, in palette index order
+pub const ANSI_KEYS: [&str; 16] = [
+    "bg2", "red", "green", "yellow",
+    "blue", "purple", "cyan", "fg2",
+    "bg4", "bright_red", "bright_green", "bright_yellow",
+    "bright_blue", "bright_purple", "bright_cyan", "fg0",
+];
+
 pub fn to_css(pal: &Palette) -> String {
     format!("
     :root {{
@@ -3,12 +3,14 @@ use std::path::PathBuf;
 mod actions;
 pub mod keybindings;
 pub mod colour_pal;
+pub mod links;
 pub mod plugins;
 mod loader;
 pub use actions::TerminalAction;
 pub use loader::*;
 pub use colour_pal::{to_css, default_pal};
 pub use plugins::*;
+pub use links::{LinkRule, default_link_rules, default_link_schemes};
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(default)]
@@ -20,6 +22,25 @@ pub struct Config {
     pub show_tabs: bool,
     pub font_size: u64,
     pub max_scrollback: u64,
+    pub link_rules: Vec<LinkRule>,
+    // Schemes links are opened for, local file links are always opened
+    pub link_schemes: Vec<String>,
+    // Opens file links, empty to use the system opener
+    pub editor: String,
+    // OSC 52, programs setting and reading the clipboard
+    pub clipboard_write: ClipboardPolicy,
+    pub clipboard_read: ClipboardPolicy,
+    // Loads scripts into bash, zsh and fish that mark prompts and report the cwd
+    pub shell_integration: bool,
+}
+
+/// If programs may use the clipboard, or if the user is asked each time
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardPolicy {
+    Allow,
+    Deny,
+    Prompt,
 }
 
 impl Default for Config {
@@ -31,7 +52,14 @@ impl Default for Config {
             start_up_command: String::new(),
             term: String::from("xterm-256color"),
             show_tabs: true,
-            palette: String::from("default")
+            palette: String::from("default"),
+            link_rules: default_link_rules(),
+            link_schemes: default_link_schemes(),
+            editor: String::new(),
+            clipboard_write: ClipboardPolicy::Allow,
+            // Reading lets any program, including ones over ssh, see what was copied
+            clipboard_read: ClipboardPolicy::Deny,
+            shell_integration: false,
         }
     }
 }
@@ -39,3 +67,13 @@ impl Default for Config {
 pub fn dir() -> PathBuf {
     dirs::config_dir().unwrap().join("prettyterm")
 }
+
+/// Expands a leading ~ to the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default();
+    match path.strip_prefix('~') {
+        Some("") => home,
+        Some(rest) if rest.starts_with('/') => home.join(rest.trim_start_matches('/')),
+        _ => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// Turns text matching a regex into a link, $N in the format is replaced by capture N
+/// File links are written as file:PATH#LINE:COLUMN, relative paths open from the command's cwd
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LinkRule {
+    pub regex: String,
+    pub format: String,
+}
+
+impl LinkRule {
+    pub fn new(regex: &str, format: &str) -> Self {
+        Self { regex: regex.to_string(), format: format.to_string() }
+    }
+}
+
+pub fn default_link_rules() -> Vec<LinkRule> {
+    vec![
+        // Urls, leaving off trailing punctuation
+        LinkRule::new(r#"\b\w+://[^\s<>"'`]*[^\s<>"'`.,;:!?)\]]"#, "$0"),
+        // Compiler style references, src/main.rs:12:5
+        LinkRule::new(
+            r"(?<![\w/.-])((?:\.{0,2}/)?[\w.-]+(?:/[\w.-]+)*\.\w+):(\d+)(?::(\d+))?",
+            "file:$1#$2:$3",
+        ),
+        // Absolute and explicitly relative paths
+        LinkRule::new(r"(?<![\w/.:-])(?:\.{1,2})?/[\w.-]+(?:/[\w.-]+)*/?", "file:$0"),
+    ]
+}
+
+pub fn default_link_schemes() -> Vec<String> {
+    vec![String::from("http"), String::from("https"), String::from("mailto")]
+}
+
+/// Fills an editor command in, {file}, {line} and {column} are replaced
+/// Lines and columns default to 1 when the link does not have them
+pub fn editor_command(editor: &str, file: &str, line: &str, column: &str) -> Vec<String> {
+    let line = if line.is_empty() { "1" } else { line };
+    let column = if column.is_empty() { "1" } else { column };
+    editor
+        .split_whitespace()
+        .map(|part| {
+            part.replace("{file}", file).replace("{line}", line).replace("{column}", column)
+        })
+        .collect()
+}
@@ -9,11 +9,12 @@ mod tabs;
 mod terminal;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::rc::Rc;
 
 use config::colour_pal::Palette;
 use config::keybindings::Keybinding;
-use config::{default_pal, to_css, Config, TerminalAction};
+use config::{default_pal, expand_home, to_css, Config, TerminalAction};
 use dioxus::desktop::{use_window, WindowBuilder, DesktopService};
 use dioxus::prelude::*;
 use input::InputManager;
@@ -28,21 +29,29 @@ use crate::tabs::{Tab, TabType};
 pub static CONFIG: GlobalSignal<Config> = Signal::global(|| config::load_config());
 pub static KEYBINDS: GlobalSignal<Vec<Keybinding>> = Signal::global(|| config::load_keybinds());
 pub static CURRENT_TAB: GlobalSignal<usize> = Signal::global(|| 0);
-pub static TABS: GlobalSignal<Vec<Tab>> = Signal::global(|| vec![Tab::new(spawn_new())]);
-pub static PTY_SYSTEM: GlobalSignal<PseudoTerminalSystem> =
-    Signal::global(|| PseudoTerminalSystem::setup());
+pub static TABS: GlobalSignal<Vec<Tab>> = Signal::global(|| vec![Tab::new(spawn_new(None))]);
+pub static PTY_SYSTEM: GlobalSignal<PseudoTerminalSystem> = Signal::global(|| {
+    let mut system = PseudoTerminalSystem::setup();
+    system.set_shell_integration(shell_integration(&CONFIG.peek()));
+    system
+});
 pub static COMMAND_PALETTE: GlobalSignal<bool> = Signal::global(|| false);
 pub static PALETTES: GlobalSignal<HashMap<String, Palette>> =
     Signal::global(|| config::load_palettes());
 pub static INPUT: GlobalSignal<InputManager> = Signal::global(InputManager::new);
 pub static WINDOW: GlobalSignal<Rc<DesktopService>> = Signal::global(|| use_window());
 
-pub fn spawn_new() -> String {
-    let mut command = None;
-    if CONFIG.read().start_up_command.is_empty() {
-        command = Some(CONFIG.read().start_up_command.clone());
-    }
-    PTY_SYSTEM.write().spawn_new(command).unwrap()
+/// Spawns a shell in a directory, or in the config's default_cwd
+pub fn spawn_new(cwd: Option<String>) -> String {
+    // An empty start up command is the default shell
+    let command = Some(CONFIG.read().start_up_command.clone());
+    let cwd = cwd.map(PathBuf::from).unwrap_or_else(|| expand_home(&CONFIG.read().default_cwd));
+    PTY_SYSTEM.write().spawn_new(command, Some(cwd)).unwrap()
+}
+
+/// Where shell integration scripts are written, None when the config turns it off
+fn shell_integration(config: &Config) -> Option<PathBuf> {
+    config.shell_integration.then(|| config::dir().join("shell"))
 }
 
 pub fn handle_action(action: TerminalAction) {
@@ -55,7 +64,8 @@ pub fn handle_action(action: TerminalAction) {
             PTY_SYSTEM.write().get(&tab.pty).write(s);
         }
         TerminalAction::NewTab => {
-            let id = spawn_new();
+            let cwd = TABS.read().get(CURRENT_TAB()).and_then(|tab| tab.cwd.clone());
+            let id = spawn_new(cwd);
             TABS.write().push(Tab::new(id));
             *CURRENT_TAB.write() = TABS.read().len() - 1;
         }
@@ -85,6 +95,7 @@ pub fn handle_action(action: TerminalAction) {
                 name: "Settings".to_string(),
                 tab_type: tabs::TabType::Menu,
                 pty: String::new(),
+                cwd: None,
             });
             *CURRENT_TAB.write() = index;
         }
@@ -94,6 +105,7 @@ pub fn handle_action(action: TerminalAction) {
                 name: "Plugins".to_string(),
                 tab_type: tabs::TabType::PluginMenu,
                 pty: String::new(),
+                cwd: None,
             });
             *CURRENT_TAB.write() = index;
         }
@@ -122,6 +134,9 @@ pub fn handle_action(action: TerminalAction) {
 
 #[component]
 pub fn App() -> Element {
+    // Only does anything when the config turns integration on or off
+    use_effect(|| PTY_SYSTEM.write().set_shell_integration(shell_integration(&CONFIG.read())));
+
     rsx! {
         style {{ include_str!("../../css/style.css") }}
         style {{ include_str!("../../css/palette.css") }}
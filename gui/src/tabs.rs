@@ -10,6 +10,8 @@ pub struct Tab {
     pub name: String,
     pub tab_type: TabType,
     pub pty: String,
+    // Local directory the shell last reported, new tabs start in it
+    pub cwd: Option<String>,
 }
 
 #[derive(Clone, PartialEq)]
@@ -25,6 +27,7 @@ impl Tab {
             name: format!("terminal"),
             tab_type: TabType::Terminal,
             pty,
+            cwd: None,
         }
     }
 }
@@ -1,23 +1,34 @@
 pub mod cell;
+pub mod colours;
 pub mod commands;
 pub mod cursor;
 pub mod debug;
+pub mod links;
+pub mod window;
 
 use cell::CellGrid;
+use colours::{load_defaults, override_style};
 use commands::CommandsSlice;
 use cursor::Cursor;
 use debug::TerminalDebug;
+use links::{link_rules, open_link};
+use window::{get_clipboard, set_clipboard, GuiWindow, WindowEvent};
 use dioxus::prelude::*;
 use pretty_hooks::{on_resize, DOMRectReadOnly};
 use serde::Deserialize;
 use crate::CONFIG;
+use config::ClipboardPolicy;
 use pretty_term::Terminal;
 use log::info;
+use std::collections::VecDeque;
 use std::{thread, time::Duration};
 use crate::{TABS, PTY_SYSTEM, INPUT};
 use dioxus_document::{Eval, Evaluator, eval};
 use pretty_hooks::wait_for_next_render;
 
+/// Most clipboard requests waiting on the user, later ones are denied
+const MAX_PROMPTS: usize = 16;
+
 #[derive(Default, Deserialize, Clone)]
 pub struct CellSize {
     pub width: f32,
@@ -27,9 +38,19 @@ pub struct CellSize {
 // TODO: split this up for the use of multiple ptys per terminal
 #[component]
 pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
-    let mut terminal = use_signal(|| Terminal::setup_no_window().unwrap());
+    let window_events = use_hook(async_channel::unbounded::<WindowEvent>);
+    let events = window_events.0.clone();
+    let mut terminal = use_signal(|| Terminal::setup(Box::new(GuiWindow::new(events))).unwrap());
+    // Clipboard access waiting on the user, when the config asks to prompt
+    // Requests are asked about one at a time, in the order they came in
+    let mut clipboard_prompt = use_signal(VecDeque::<WindowEvent>::new);
     let debug = use_signal(|| false);
-    let cursor_pos = use_memo(move || terminal.read().cursor_pos());
+    // Lines are rendered by line number, so the cursor is found by it too
+    let cursor_pos = use_memo(move || {
+        let terminal = terminal.read();
+        let (x, y) = terminal.cursor_pos();
+        (x, terminal.screen().line_number(y))
+    });
     let pty = use_signal(|| pty);
 
     use_effect(move || {
@@ -40,6 +61,29 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
         TABS.write()[index].name = terminal.read().title.clone();
     });
 
+    // Remote directories from ssh sessions can't be spawned into
+    use_effect(move || {
+        let cwd = terminal.read().state.cwd.clone().filter(|cwd| cwd.is_local());
+        let cwd = cwd.map(|cwd| cwd.path);
+        if TABS.peek()[index].cwd != cwd {
+            TABS.write()[index].cwd = cwd;
+        }
+    });
+
+    use_effect(move || {
+        let max_scrollback = CONFIG.read().max_scrollback as usize;
+        terminal.write().set_max_scrollback(max_scrollback);
+    });
+
+    use_effect(move || {
+        let schemes = CONFIG.read().link_schemes.clone();
+        terminal.write().set_link_rules(link_rules(), schemes);
+    });
+
+    use_effect(move || {
+        load_defaults(&mut terminal.write().colours);
+    });
+
     // Cell Size Reader
     let mut size_style = use_signal(|| String::new());
     let cell_size = use_resource(move || async move {
@@ -69,6 +113,7 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
             let (rows, cols) = PTY_SYSTEM.write().get(&pty()).resize(width, height, cell.width, cell.height);
             info!("Resize Event, {rows}:{cols}");
             terminal.write().resize(rows, cols);
+            terminal.write().set_cell_size(cell.width.round() as u32, cell.height.round() as u32);
         }
     });
 
@@ -85,6 +130,9 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
                     document.getElementById('split-{pty}').dispatchEvent(new Event(\"scrollCheck\"));
                 "));
                 terminal.write().handle_actions(a.clone());
+                for response in terminal.write().drain_responses() {
+                    PTY_SYSTEM.write().get(&pty()).write(response);
+                }
                 wait_for_next_render().await;
                 eval(&format!("
                     document.getElementById('split-{pty}').dispatchEvent(new Event(\"termUpdate\"));
@@ -93,6 +141,40 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
         }
     });
 
+    // Hyperlink hovering, with Ctrl+click opening links
+    use_future(move || async move {
+        wait_for_next_render().await;
+
+        let mut links = eval(include_str!("../../js/hyperlinks.js"));
+        links.send(format!("split-{pty}")).unwrap();
+        while let Ok(uri) = links.recv().await {
+            if let Some(uri) = uri.as_str() {
+                open_link(uri);
+            }
+        }
+    });
+
+    // Clipboard requests from OSC 52, checked against the config's policy
+    use_future(move || {
+        let events = window_events.1.clone();
+        async move {
+            while let Ok(event) = events.recv().await {
+                let policy = match event {
+                    WindowEvent::SetClipboard(..) => CONFIG.read().clipboard_write,
+                    WindowEvent::RequestClipboard(_) => CONFIG.read().clipboard_read,
+                };
+                match policy {
+                    ClipboardPolicy::Allow => window_event(terminal, pty(), event),
+                    ClipboardPolicy::Prompt if clipboard_prompt.peek().len() >= MAX_PROMPTS => {
+                        info!("Too many clipboard requests waiting, denied {event:?}")
+                    }
+                    ClipboardPolicy::Prompt => clipboard_prompt.write().push_back(event),
+                    ClipboardPolicy::Deny => info!("Denied clipboard access {event:?}"),
+                }
+            }
+        }
+    });
+
     // Terminal Auto Scroll
     use_future(move || async move {
         wait_for_next_render().await;
@@ -125,9 +207,14 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
         "))
     });
 
+    let prompt_message = clipboard_prompt.read().front().map(|event| match event {
+        WindowEvent::SetClipboard(..) => "A program wants to set the clipboard",
+        WindowEvent::RequestClipboard(_) => "A program wants to read the clipboard",
+    });
+
     rsx! {
         div {
-            style: "{size_style.read()}",
+            style: "{size_style.read()} {override_style(&terminal.read().colours)}",
             class: "terminal-split",
             class: if terminal.read().state.alt_screen { "alt-screen" },
             id: "split-{pty}",
@@ -140,6 +227,30 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
                 CommandsSlice { terminal }
             }
 
+            if let Some(message) = prompt_message {
+                div {
+                    class: "confirmselect",
+                    p { "{message}" }
+                    button {
+                        class: "confirmbutton",
+                        onclick: move |_| {
+                            let event = clipboard_prompt.write().pop_front();
+                            if let Some(event) = event {
+                                window_event(terminal, pty(), event);
+                            }
+                        },
+                        "Allow"
+                    }
+                    button {
+                        class: "cancelbutton",
+                        onclick: move |_| {
+                            clipboard_prompt.write().pop_front();
+                        },
+                        "Deny"
+                    }
+                }
+            }
+
             if terminal.read().state.show_cursor {
                 Cursor {
                     cursor_pos,
@@ -153,3 +264,18 @@ pub fn TerminalApp(pty: String, hidden: bool, index: usize) -> Element {
         }
     }
 }
+
+/// Carries out an allowed window request, writing any reply straight to the pty
+fn window_event(mut terminal: Signal<Terminal>, pty: String, event: WindowEvent) {
+    match event {
+        WindowEvent::SetClipboard(clipboard, text) => set_clipboard(clipboard, text),
+        WindowEvent::RequestClipboard(clipboard) => {
+            if let Some(text) = get_clipboard(clipboard) {
+                terminal.write().clipboard_reply(clipboard, &text);
+            }
+            for response in terminal.write().drain_responses() {
+                PTY_SYSTEM.write().get(&pty).write(response);
+            }
+        }
+    }
+}
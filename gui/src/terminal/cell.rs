@@ -1,38 +1,60 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 use dioxus::prelude::*;
-use pretty_term::cell::{CellAttributes};
+use pretty_term::cell::{AttrId, PromptKind, SemanticType, Until};
+use pretty_term::colours::{hex_string, xterm_colour};
 use pretty_term::Terminal;
 use termwiz::color::ColorSpec;
+use termwiz::hyperlink::Hyperlink;
 
 #[component]
 pub fn CellGrid(terminal: Signal<Terminal>) -> Element {
     let scrollback = use_signal(|| 0);
+    let term = terminal.read();
+    let screen = term.screen();
 
     rsx! {
         pre {
             class: "cells",
             overflow_y: "overflow",
 
-            for y in terminal.read().screen().scroll_range(scrollback()) {
-                CellLine { terminal, y }
+            for y in screen.scroll_range(scrollback()) {
+                CellLine {
+                    key: "{screen.line_number(y)}",
+                    terminal,
+                    line: screen.line_number(y),
+                    generation: screen.line_generation(y),
+                }
             }
         }
     }
 }
 
+/// A single line of cells, found by its line number so it survives scrollback trimming
+/// Peeks at the terminal so it only rerenders when its generation changes
 #[component]
-pub fn CellLine(terminal: Signal<Terminal>, y: usize) -> Element {
-    let term = terminal.read();
-    let mut line = term.screen().line(y).unwrap().iter();
-    let mut last_attr = CellAttributes::default();
+pub fn CellLine(terminal: Signal<Terminal>, line: usize, generation: u64) -> Element {
+    let _ = generation;
+    let term = terminal.peek();
+    let screen = term.screen();
+    let Some(index) = screen.line_index(line) else {
+        return rsx! {};
+    };
+    let attrs = &term.renderer.attrs;
+    let mut last_attr = attrs.get(AttrId::default());
     let mut open = false;
     let mut rendered = String::new();
 
-    while let Some(cell) = line.next() {
+    // Attributes only change between runs, so tags are worked out once per run
+    for (id, cells) in screen.line(index).unwrap().runs() {
+        let attr = attrs.get(id);
+
         // Every bit in attributes, associated with a certain tag
         // Multibit attributes are ignored
         for i in 0..13 {
             let last = last_attr.get_bit(i);
-            let current = cell.attr.get_bit(i);
+            let current = attr.get_bit(i);
             let tag = get_tag(i);
 
             match (last, current) {
@@ -43,30 +65,132 @@ pub fn CellLine(terminal: Signal<Terminal>, y: usize) -> Element {
         }
 
         // TODO: macro for colours?
-        // FG Differences
-        if cell.attr.get_fg() != last_attr.get_fg() || cell.attr.get_bg() != last_attr.get_bg() {
-            let fg = cell.attr.get_fg().to_hex("var(--fg-default)".to_string());
-            let bg = cell.attr.get_bg().to_hex("var(--bg-default)".to_string());
+        // FG Differences, spans also carry the semantic zone so themes can style prompts
+        let sem = attr.semantic_type();
+        if attr.get_fg() != last_attr.get_fg()
+            || attr.get_bg() != last_attr.get_bg()
+            || sem != last_attr.semantic_type()
+        {
+            let fg = attr.get_fg().to_hex("var(--fg-default)".to_string());
+            let bg = attr.get_bg().to_hex("var(--bg-default)".to_string());
+            let class = semantic_class(&sem);
             if open {
                 rendered.push_str("</span>");
             }
-            rendered.push_str(&format!("<span class= \"cellspan\" style=\"--fg: {fg}; --bg: {bg};\">"));
+            rendered.push_str(&format!(
+                "<span class= \"cellspan {class}\" style=\"--fg: {fg}; --bg: {bg};\">"
+            ));
             open = true;
         }
 
-        rendered.push(cell.text);
-        last_attr = cell.attr.clone();
+        // Links wrap just the run's text so they nest inside the other tags
+        let link = attr.hyperlink();
+        if let Some(link) = link {
+            let key = link_key(link);
+            rendered.push_str(&format!("<a class=\"hyperlink\" data-link=\"{key}\" href=\""));
+            push_escaped(&mut rendered, link.uri());
+            rendered.push_str("\" title=\"");
+            push_escaped(&mut rendered, link.uri());
+            rendered.push_str("\">");
+        }
+
+        // The wide character before a spacer already covers its column
+        for cell in cells.iter().filter(|cell| !cell.is_spacer()) {
+            if cell.is_placeholder() {
+                // Kitty placeholders are covered by their image
+                rendered.push(' ');
+            } else if cell.width() > 1 {
+                rendered.push_str("<span class=\"wide\">");
+                push_escaped(&mut rendered, &cell.text);
+                rendered.push_str("</span>");
+            } else {
+                push_escaped(&mut rendered, &cell.text);
+            }
+        }
+        if link.is_some() {
+            rendered.push_str("</a>");
+        }
+        last_attr = attr;
     }
 
+    // Images are drawn over the cells from the line they start on
+    let images = screen
+        .images_at(index)
+        .map(|image| {
+            let style = format!(
+                "--column: {}; width: {}px; height: {}px; margin: {}px 0 0 {}px; z-index: {};",
+                image.x, image.width, image.height, image.offset.1, image.offset.0, image.z_index
+            );
+            (style, image.image.data_url().to_string())
+        })
+        .collect::<Vec<_>>();
+
+    // Placeholder cells each show a slice of an image stretched over its cells
+    let placeholders = term
+        .placeholders(index)
+        .into_iter()
+        .map(|run| {
+            let style = format!(
+                "--column: {}; --width: {}; --col: {}; --row: {}; --cols: {}; --rows: {};",
+                run.x, run.width, run.col, run.row, run.cols, run.rows
+            );
+            (style, run.image.data_url().to_string())
+        })
+        .collect::<Vec<_>>();
+
     rsx! {
         div {
             font_size: "14px",
-            id: "line_{y}",
-            dangerous_inner_html: rendered,
+            id: "line_{line}",
+            position: "relative",
+            span { dangerous_inner_html: rendered }
+            for (style, url) in images {
+                img { class: "terminal-image", style, src: url }
+            }
+            for (style, url) in placeholders {
+                div { class: "image-slice", style,
+                    img { src: url }
+                }
+            }
+        }
+    }
+}
+
+/// Pushes text into rendered html, escaping anything html would parse
+pub fn push_escaped(rendered: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => rendered.push_str("&lt;"),
+            '>' => rendered.push_str("&gt;"),
+            '&' => rendered.push_str("&amp;"),
+            '"' => rendered.push_str("&quot;"),
+            _ => rendered.push(c),
         }
     }
 }
 
+/// Key shared by every part of a link, so a link split across lines highlights as one
+/// OSC 8 links with the same id and uri are the same link, links without an id group by uri
+pub fn link_key(link: &Hyperlink) -> String {
+    let mut hasher = DefaultHasher::new();
+    link.params().get("id").hash(&mut hasher);
+    link.uri().hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+/// Classes for a semantic zone, every prompt also has the prompt class
+pub fn semantic_class(sem: &SemanticType) -> &'static str {
+    match sem {
+        SemanticType::Output => "sem-output",
+        SemanticType::Input(Until::LineEnd) => "sem-input sem-input-line",
+        SemanticType::Input(Until::SemanticMarker) => "sem-input",
+        SemanticType::Prompt(PromptKind::Initial) => "sem-prompt",
+        SemanticType::Prompt(PromptKind::RightSide) => "sem-prompt sem-prompt-right",
+        SemanticType::Prompt(PromptKind::Continuation) => "sem-prompt sem-prompt-continuation",
+        SemanticType::Prompt(PromptKind::Secondary) => "sem-prompt sem-prompt-secondary",
+    }
+}
+
 pub trait ToHex {
     fn to_hex(&self, def: String) -> String;
 }
@@ -76,7 +200,11 @@ impl ToHex for ColorSpec {
         match self {
             ColorSpec::TrueColor(c) => c.to_string(),
             ColorSpec::Default => def,
-            ColorSpec::PaletteIndex(i) => format!("var(--palette-{i})"),
+            // Only the first 16 come from the palette css, the rest fall back to xterm's
+            ColorSpec::PaletteIndex(i) if *i < 16 => format!("var(--palette-{i})"),
+            ColorSpec::PaletteIndex(i) => {
+                format!("var(--palette-{i}, {})", hex_string(xterm_colour(*i)))
+            }
         }
     }
 }
@@ -92,7 +220,6 @@ pub fn get_tag(tag: u8) -> String {
         6 => "hide",
         7 => "underline",
         8 => "doubleunderline",
-        9 => "wrapped",
         10 => "super",
         11 => "sub",
         12 => "blink",
@@ -0,0 +1,49 @@
+use config::colour_pal::ANSI_KEYS;
+use config::default_pal;
+use pretty_term::colours::{hex_string, to_rgb, TerminalColours};
+use termwiz::color::SrgbaTuple;
+
+use crate::{CONFIG, PALETTES};
+
+/// Fills the terminal's default colours in from the active palette
+/// These are what queries report and what resets go back to
+pub fn load_defaults(colours: &mut TerminalColours) {
+    let palettes = PALETTES.read();
+    let palette = palettes.get(&CONFIG.read().palette).cloned().unwrap_or_else(default_pal);
+    let colour = |key: &str| {
+        let hex = palette.get(key)?;
+        hex.parse::<SrgbaTuple>().ok().map(to_rgb)
+    };
+
+    for (index, key) in ANSI_KEYS.iter().enumerate() {
+        if let Some(rgb) = colour(key) {
+            colours.defaults[index] = rgb;
+        }
+    }
+    if let Some(fg) = colour("fg0") {
+        colours.default_fg = fg;
+        colours.default_cursor = fg;
+    }
+    if let Some(bg) = colour("bg0") {
+        colours.default_bg = bg;
+    }
+}
+
+/// Css variables for the colours applications have changed, set on the terminal's split
+pub fn override_style(colours: &TerminalColours) -> String {
+    let mut style = String::new();
+    for (index, rgb) in &colours.palette {
+        style.push_str(&format!("--palette-{index}: {}; ", hex_string(*rgb)));
+    }
+    let dynamic = [
+        ("fg-default", colours.fg),
+        ("bg-default", colours.bg),
+        ("cursor-colour", colours.cursor),
+    ];
+    for (name, rgb) in dynamic {
+        if let Some(rgb) = rgb {
+            style.push_str(&format!("--{name}: {}; ", hex_string(rgb)));
+        }
+    }
+    style
+}
@@ -10,7 +10,7 @@ pub fn CommandsSlice(terminal: Signal<Terminal>) -> Element {
 
     rsx! {
         for command in terminal.read().commands.get() {
-            Command { command: *command, terminal }
+            Command { command: command.clone(), terminal }
             hr { class: "command-sep" }
         }
     }
@@ -40,6 +40,8 @@ pub fn Command(
         CommandStatus::FatalError(_) => "command-fatal",
         CommandStatus::None => "",
     };
+    let term = terminal.read();
+    let screen = term.screen();
 
     rsx! {
         div {
@@ -48,8 +50,13 @@ pub fn Command(
             onmouseleave: move  |_| hovering.set(false),
 
             pre {
-                for y in command.range(terminal.read().screen().scrollback_len()) {
-                    CellLine { terminal, y }
+                for y in command.range(screen.scrollback_len()) {
+                    CellLine {
+                        key: "{screen.line_number(y)}",
+                        terminal,
+                        line: screen.line_number(y),
+                        generation: screen.line_generation(y),
+                    }
                 }
             }
 
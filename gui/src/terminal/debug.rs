@@ -30,6 +30,10 @@ pub fn TerminalDebug(terminal: Signal<Terminal>) -> Element {
                     td { "Commands" }
                     td { "{terminal.read().commands.len()}" }
                 }
+                tr {
+                    td { "Memory" }
+                    td { "{terminal.read().memory_usage()} bytes" }
+                }
                 for (key, value) in &terminal.read().state.dec_modes {
                     tr {
                         td { "{as_dec(key):?}", }
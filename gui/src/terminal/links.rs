@@ -0,0 +1,66 @@
+use config::links::editor_command;
+use log::warn;
+use pretty_term::links::{link_allowed, link_scheme};
+use pretty_term::state::WorkingDirectory;
+use termwiz::hyperlink::Rule;
+
+use crate::CONFIG;
+
+/// Compiles the configured link rules, skipping any with a bad regex
+pub fn link_rules() -> Vec<Rule> {
+    CONFIG
+        .read()
+        .link_rules
+        .iter()
+        .filter_map(|rule| match Rule::new(&rule.regex, &rule.format) {
+            Ok(rule) => Some(rule),
+            Err(err) => {
+                warn!("Invalid link rule {}: {err}", rule.regex);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Opens a link in the system opener, if its scheme is in the allowed link schemes
+/// Local file links go to the configured editor instead, at the line in their fragment
+pub fn open_link(uri: &str) {
+    let (editor, schemes) = {
+        let config = CONFIG.read();
+        (config.editor.clone(), config.link_schemes.clone())
+    };
+
+    if !link_allowed(uri, &schemes) {
+        warn!("Not opening {uri}, it is on another machine or its scheme is not allowed");
+        return;
+    }
+    if link_scheme(uri).is_none_or(|scheme| scheme != "file") {
+        if let Err(err) = open::that_detached(uri) {
+            warn!("Failed to open {uri}: {err}");
+        }
+        return;
+    }
+
+    // Full file urls carry a host, links that couldn't be resolved are just a path
+    let (target, position) = uri.split_once('#').unwrap_or((uri, ""));
+    let target = &target["file:".len()..];
+    let path = match WorkingDirectory::parse(&format!("file:{target}")) {
+        Some(url) => url.path,
+        None => target.to_string(),
+    };
+
+    if editor.is_empty() {
+        // The system opener does not understand line fragments
+        if let Err(err) = open::that_detached(&path) {
+            warn!("Failed to open {uri}: {err}");
+        }
+        return;
+    }
+    let (line, column) = position.split_once(':').unwrap_or((position, ""));
+    let command = editor_command(&editor, &path, line, column);
+    if let Some((program, args)) = command.split_first() {
+        if let Err(err) = std::process::Command::new(program).args(args).spawn() {
+            warn!("Failed to run editor {editor}: {err}");
+        }
+    }
+}
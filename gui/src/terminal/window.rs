@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+
+use async_channel::Sender;
+use log::warn;
+use pretty_term::window::{Clipboard, WindowHandler};
+
+/// Requests from the terminal that need the GUI, handled by the terminal's component
+#[derive(Debug)]
+pub enum WindowEvent {
+    SetClipboard(Clipboard, String),
+    RequestClipboard(Clipboard),
+}
+
+/// Window handler passing requests on to the terminal's component
+pub struct GuiWindow {
+    events: Sender<WindowEvent>,
+}
+
+impl GuiWindow {
+    pub fn new(events: Sender<WindowEvent>) -> GuiWindow { GuiWindow { events } }
+}
+
+impl WindowHandler for GuiWindow {
+    fn set_clipboard(&mut self, clipboard: Clipboard, text: String) {
+        let _ = self.events.try_send(WindowEvent::SetClipboard(clipboard, text));
+    }
+
+    fn request_clipboard(&mut self, clipboard: Clipboard) {
+        let _ = self.events.try_send(WindowEvent::RequestClipboard(clipboard));
+    }
+}
+
+thread_local! {
+    // Kept alive so copied text is still served after the call returns
+    static CLIPBOARD: RefCell<Option<arboard::Clipboard>> = const { RefCell::new(None) };
+}
+
+fn with_clipboard<T>(
+    f: impl FnOnce(&mut arboard::Clipboard) -> Result<T, arboard::Error>,
+) -> Option<T> {
+    CLIPBOARD.with_borrow_mut(|clipboard| {
+        if clipboard.is_none() {
+            *clipboard = arboard::Clipboard::new()
+                .map_err(|err| warn!("Failed to open the clipboard: {err}"))
+                .ok();
+        }
+        f(clipboard.as_mut()?).map_err(|err| warn!("Clipboard error: {err}")).ok()
+    })
+}
+
+/// Writes to a clipboard, primary is only separate on Linux
+pub fn set_clipboard(clipboard: Clipboard, text: String) {
+    with_clipboard(|board| {
+        #[cfg(target_os = "linux")]
+        {
+            use arboard::SetExtLinux;
+            board.set().clipboard(linux_kind(clipboard)).text(text)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = clipboard;
+            board.set_text(text)
+        }
+    });
+}
+
+pub fn get_clipboard(clipboard: Clipboard) -> Option<String> {
+    with_clipboard(|board| {
+        #[cfg(target_os = "linux")]
+        {
+            use arboard::GetExtLinux;
+            board.get().clipboard(linux_kind(clipboard)).text()
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = clipboard;
+            board.get_text()
+        }
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn linux_kind(clipboard: Clipboard) -> arboard::LinuxClipboardKind {
+    match clipboard {
+        Clipboard::Clipboard => arboard::LinuxClipboardKind::Clipboard,
+        Clipboard::Primary => arboard::LinuxClipboardKind::Primary,
+    }
+}
@@ -0,0 +1,31 @@
+// Hyperlinks in a terminal split
+// Parts of one link share a data-link key, so they highlight together across lines
+const split = await dioxus.recv();
+const termWindow = document.getElementById(split);
+
+function setHover(link, hover) {
+    const parts = termWindow.querySelectorAll(`a.hyperlink[data-link="${link.dataset.link}"]`);
+    for (const part of parts) {
+        part.classList.toggle("hover", hover);
+    }
+}
+
+termWindow.addEventListener("mouseover", (event) => {
+    const link = event.target.closest("a.hyperlink");
+    if (link) { setHover(link, true); }
+});
+
+termWindow.addEventListener("mouseout", (event) => {
+    const link = event.target.closest("a.hyperlink");
+    if (link) { setHover(link, false); }
+});
+
+// The webview must never navigate, links are only opened with ctrl held
+termWindow.addEventListener("click", (event) => {
+    const link = event.target.closest("a.hyperlink");
+    if (!link) { return; }
+    event.preventDefault();
+    if (event.ctrlKey) {
+        dioxus.send(link.getAttribute("href"));
+    }
+});
@@ -0,0 +1,86 @@
+# PreTTY shell integration for bash 4.4 and newer
+# Marks prompts, input and output with OSC 133, reports the working directory with OSC 7
+# and sets iTerm2 user vars for the user and host
+# PreTTY loads this itself when shell_integration is on, otherwise source it from ~/.bashrc
+
+# PreTTY starts bash in posix mode with this as ENV, which replaces every startup file
+# Posix mode is turned back off and the files bash would have read are loaded first
+if [[ -n $PRETTY_BASH_INJECT ]]; then
+    unset PRETTY_BASH_INJECT
+    if [[ -n $PRETTY_USER_ENV ]]; then export ENV=$PRETTY_USER_ENV; else unset ENV; fi
+    unset PRETTY_USER_ENV
+    set +o posix
+    shopt -u inherit_errexit 2>/dev/null
+    if [[ -n $PRETTY_BASH_UNEXPORT_HISTFILE ]]; then
+        export -n HISTFILE
+        unset PRETTY_BASH_UNEXPORT_HISTFILE
+    fi
+
+    if shopt -q login_shell; then
+        [[ -r /etc/profile ]] && . /etc/profile
+        # Only the first of these that exists, like bash itself
+        for __pretty_file in ~/.bash_profile ~/.bash_login ~/.profile; do
+            if [[ -r $__pretty_file ]]; then
+                . "$__pretty_file"
+                break
+            fi
+        done
+        unset __pretty_file
+    else
+        [[ -r /etc/bash.bashrc ]] && . /etc/bash.bashrc
+        [[ -r ~/.bashrc ]] && . ~/.bashrc
+    fi
+fi
+
+[[ $- == *i* ]] || return 0
+[[ -z $PRETTY_INTEGRATION_LOADED ]] || return 0
+PRETTY_INTEGRATION_LOADED=1
+
+# Encoded a byte at a time so paths with spaces or unicode survive the url
+__pretty_urlencode() {
+    local LC_ALL=C c i
+    for (( i = 0; i < ${#1}; i++ )); do
+        c=${1:i:1}
+        case $c in
+            [a-zA-Z0-9/._~-]) printf '%s' "$c" ;;
+            *) printf '%%%02X' "'$c" ;;
+        esac
+    done
+}
+
+__pretty_user_var() {
+    printf '\e]1337;SetUserVar=%s=%s\a' "$1" "$(printf '%s' "$2" | base64 | tr -d '\n')"
+}
+
+# Runs first, keeping the status for the rest of PROMPT_COMMAND
+__pretty_save_status() {
+    __pretty_status=$?
+    return $__pretty_status
+}
+
+__pretty_precmd() {
+    # PS0 sets this as a command starts, so an empty line doesn't report a status
+    if [[ -n $__pretty_running ]]; then
+        printf '\e]133;D;%s\a' "$__pretty_status"
+        __pretty_running=
+    fi
+    printf '\e]7;file://%s%s\a' "$HOSTNAME" "$(__pretty_urlencode "$PWD")"
+
+    # Themes rewrite the prompt, so the marks are put back whenever they go missing
+    if [[ $PS1 != *'133;B'* ]]; then
+        PS1='\[\e]133;A\a\]'$PS1'\[\e]133;B\a\]'
+    fi
+    if [[ $PS0 != *'133;C'* ]]; then
+        # The subscript is evaluated by the shell itself, marking the command as running
+        PS0+='\e]133;C\a${__pretty_noop[__pretty_running=1]}'
+    fi
+    return $__pretty_status
+}
+
+__pretty_user_var user "$USER"
+__pretty_user_var host "$HOSTNAME"
+
+# Newlines rather than semicolons, in case PROMPT_COMMAND already ends with one
+PROMPT_COMMAND="__pretty_save_status
+${PROMPT_COMMAND:+$PROMPT_COMMAND
+}__pretty_precmd"
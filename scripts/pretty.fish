@@ -0,0 +1,48 @@
+# PreTTY shell integration for fish
+# Marks prompts, input and output with OSC 133, reports the working directory with OSC 7
+# and sets iTerm2 user vars for the user and host
+# PreTTY loads this itself when shell_integration is on, otherwise source it from config.fish
+
+status is-interactive; or exit
+set -q PRETTY_INTEGRATION_LOADED; and exit
+set -g PRETTY_INTEGRATION_LOADED 1
+
+function __pretty_user_var
+    printf '\e]1337;SetUserVar=%s=%s\a' $argv[1] (printf '%s' $argv[2] | base64 | tr -d '\n')
+end
+
+function __pretty_return
+    return $argv[1]
+end
+
+function __pretty_preexec --on-event fish_preexec
+    set -g __pretty_running 1
+    printf '\e]133;C\a'
+end
+
+function __pretty_postexec --on-event fish_postexec
+    set -l last_status $status
+    if set -q __pretty_running
+        printf '\e]133;D;%s\a' $last_status
+        set -e __pretty_running
+    end
+end
+
+function __pretty_cwd --on-event fish_prompt
+    printf '\e]7;file://%s%s\a' $hostname (string escape --style=url -- $PWD)
+end
+
+# The prompt is wrapped rather than replaced, keeping the status it shows
+functions -q fish_prompt; and functions -c fish_prompt __pretty_prompt
+function fish_prompt
+    set -l last_status $status
+    printf '\e]133;A\a'
+    if functions -q __pretty_prompt
+        __pretty_return $last_status
+        __pretty_prompt
+    end
+    printf '\e]133;B\a'
+end
+
+__pretty_user_var user $USER
+__pretty_user_var host $hostname
@@ -1,8 +1,16 @@
-use termwiz::cell::{Blink, Intensity, Underline, VerticalAlign};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+use termwiz::cell::{grapheme_column_width, Blink, Intensity, Underline, VerticalAlign};
 use termwiz::color::{ColorSpec, SrgbaTuple};
 use termwiz::escape::csi::Font;
 use termwiz::escape::osc::FinalTermPromptKind;
 use termwiz::hyperlink::Hyperlink;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::kitty::PLACEHOLDER;
 
 /// A Node system for dealing with terminal output
 /// Unsure if it should be a syntax tree or just have splitter members in it
@@ -49,7 +57,7 @@ pub enum SemanticType {
     Prompt(PromptKind),
 }
 
-#[derive(Clone, Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub enum Color {
     #[default]
     Default,
@@ -71,13 +79,14 @@ pub struct CellAttributes {
     // bit 6 = hide
     // bit 7 = underline
     // bit 8 = double underline
-    // bit 9 = wrapped
+    // bit 9 = unused, soft wraps are kept on the Line
     // bit 10 = superscript
     // bit 11 = subscript
     // bit 12 = slow blink
     // bit 13 = fast_blink
-    // bit 14 15 = semantic type
-    attributes: u16,
+    // bit 14 15 = semantic type, output, input or prompt
+    // bit 16 17 = prompt kind, or bit 16 for input that lasts until the next marker
+    attributes: u32,
     pub extra: Option<Box<ExtraAttributes>>,
 }
 
@@ -162,38 +171,34 @@ impl CellAttributes {
     bitfield!(hide, set_hide, 6);
     bitfield!(single_underline, set_single_ul, 7);
     bitfield!(double_underline, set_double_ul, 8);
-    bitfield!(wrapped, set_wrapped, 9);
     bitfield!(superscript, set_super, 10);
     bitfield!(subscript, set_sub, 11);
     bitfield!(slow_blink, set_slow_blink, 12);
     bitfield!(rapid_blink, set_rapid_blink, 13);
 
     pub fn set_sem_type(&mut self, sem: SemanticType) {
-        match sem {
-            SemanticType::Output => {
-                self.set_bit(14, false);
-                self.set_bit(15, false);
-            }
-            SemanticType::Input(_) => {
-                self.set_bit(14, true);
-                self.set_bit(15, false);
-            }
-            SemanticType::Prompt(_) => {
-                self.set_bit(14, false);
-                self.set_bit(15, true);
-            }
-        }
+        let (kind, detail) = match sem {
+            SemanticType::Output => (0, 0),
+            SemanticType::Input(Until::LineEnd) => (1, 0),
+            SemanticType::Input(Until::SemanticMarker) => (1, 1),
+            SemanticType::Prompt(prompt) => (2, prompt as u32),
+        };
+        self.attributes = (self.attributes & !(0b1111 << 14)) | ((detail << 2 | kind) << 14);
     }
 
     pub fn semantic_type(&self) -> SemanticType {
-        let first_bit = self.get_bit(14);
-        let second_bit = self.get_bit(15);
-
-        match (first_bit, second_bit) {
-            (false, false) => SemanticType::Output,
-            (true, false) => SemanticType::Input(Until::LineEnd),
-            (false, true) => SemanticType::Prompt(PromptKind::Initial),
-            _ => panic!("Semantic type bits not set properly"),
+        let detail = (self.attributes >> 16) & 0b11;
+        match (self.attributes >> 14) & 0b11 {
+            1 if detail == 1 => SemanticType::Input(Until::SemanticMarker),
+            1 => SemanticType::Input(Until::LineEnd),
+            2 => SemanticType::Prompt(match detail {
+                0 => PromptKind::Initial,
+                1 => PromptKind::RightSide,
+                2 => PromptKind::Continuation,
+                _ => PromptKind::Secondary,
+            }),
+            // Both bits are never set together, but treat it as output rather than panic
+            _ => SemanticType::Output,
         }
     }
 
@@ -251,8 +256,23 @@ impl CellAttributes {
             self.get_extra().underline_fg = Some(colour);
     }
 
-    pub fn set_hyperlink(&mut self, link: Option<Hyperlink>) { 
-            self.get_extra().hyperlink = link;
+    /// OSC 8 link, clearing it does not allocate extra attributes
+    pub fn set_hyperlink(&mut self, link: Option<Hyperlink>) {
+        if link.is_none() && self.extra.is_none() {
+            return;
+        }
+        self.get_extra().hyperlink = link;
+    }
+
+    pub fn hyperlink(&self) -> Option<&Hyperlink> {
+        self.extra.as_ref().and_then(|extra| extra.hyperlink.as_ref())
+    }
+
+    /// Attributes of an erased cell, which only keeps the background colour
+    pub fn background(&self) -> CellAttributes {
+        let mut attr = CellAttributes::default();
+        attr.set_bg(self.get_bg());
+        attr
     }
 
     pub fn get_extra(&mut self) -> &mut Box<ExtraAttributes> {
@@ -262,28 +282,262 @@ impl CellAttributes {
         self.extra.as_mut().unwrap()
     }
 
-    pub fn hash(&self) -> String { format!("{:?}:{:?}:{}", self.fg, self.bg, self.attributes) }
+    /// Short description of the colours and bits, for snapshots and debugging
+    pub fn describe(&self) -> String { format!("{:?}:{:?}:{}", self.fg, self.bg, self.attributes) }
+}
+
+impl Eq for CellAttributes {}
+
+impl Hash for CellAttributes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bg.hash(state);
+        self.fg.hash(state);
+        self.underline_fg.hash(state);
+        self.attributes.hash(state);
+        self.extra.hash(state);
+    }
+}
+
+impl Hash for ExtraAttributes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.font {
+            Font::Default => None,
+            Font::Alternate(font) => Some(font),
+        }
+        .hash(state);
+        self.fg.hash(state);
+        self.bg.hash(state);
+        match self.underline_fg {
+            None | Some(ColorSpec::Default) => {}
+            Some(ColorSpec::PaletteIndex(index)) => index.hash(state),
+            Some(ColorSpec::TrueColor(colour)) => colour.hash(state),
+        }
+        // Link params are a HashMap with no fixed order, the id is the one that matters
+        if let Some(link) = &self.hyperlink {
+            link.uri().hash(state);
+            link.params().get("id").hash(state);
+            link.is_implicit().hash(state);
+        }
+    }
+}
+
+/// Index into an AttributeTable, 0 is always the default attributes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub struct AttrId(pub u32);
+
+/// Every distinct set of attributes used by cells, stored once
+/// Cells only keep an AttrId, so a screen full of the same style costs 4 bytes a cell
+#[derive(Debug)]
+pub struct AttributeTable {
+    attrs: Vec<CellAttributes>,
+    ids: HashMap<CellAttributes, AttrId>,
+}
+
+impl AttributeTable {
+    pub fn new() -> Self {
+        let default = CellAttributes::default();
+        AttributeTable {
+            attrs: vec![default.clone()],
+            ids: HashMap::from([(default, AttrId(0))]),
+        }
+    }
+
+    /// The id for a set of attributes, adding them if they are new
+    pub fn intern(&mut self, attr: &CellAttributes) -> AttrId {
+        if let Some(id) = self.ids.get(attr) {
+            return *id;
+        }
+        let id = AttrId(self.attrs.len() as u32);
+        self.attrs.push(attr.clone());
+        self.ids.insert(attr.clone(), id);
+        id
+    }
+
+    pub fn get(&self, id: AttrId) -> &CellAttributes { &self.attrs[id.0 as usize] }
+
+    pub fn len(&self) -> usize { self.attrs.len() }
+
+    /// Keeps only the attributes marked as used, returning the new id for each old one
+    /// Ids that were dropped map to the default attributes
+    pub fn retain(&mut self, used: &[bool]) -> Vec<AttrId> {
+        let mut ids = vec![AttrId(0); self.attrs.len()];
+        let attrs = std::mem::take(&mut self.attrs);
+        self.ids.clear();
+
+        for (old, attr) in attrs.into_iter().enumerate() {
+            if old == 0 || used.get(old).copied().unwrap_or(false) {
+                ids[old] = AttrId(self.attrs.len() as u32);
+                self.ids.insert(attr.clone(), ids[old]);
+                self.attrs.push(attr);
+            }
+        }
+        self.attrs.shrink_to_fit();
+        self.ids.shrink_to_fit();
+        ids
+    }
+
+    /// Roughly how many bytes the table is using
+    pub fn memory_usage(&self) -> usize {
+        let attr_size = std::mem::size_of::<CellAttributes>();
+        let extra_size = std::mem::size_of::<ExtraAttributes>();
+        let extras = self.attrs.iter().filter_map(|attr| attr.extra.as_ref());
+        let extras = extras
+            .map(|extra| {
+                let link = extra.hyperlink.as_ref().map_or(0, |link| {
+                    let params = link.params().iter().map(|(key, value)| key.len() + value.len());
+                    link.uri().len() + params.sum::<usize>()
+                });
+                extra_size + link
+            })
+            .sum::<usize>();
+        // Each set of attributes is in both the list and the map
+        (self.attrs.capacity() + self.ids.capacity()) * attr_size
+            + self.ids.capacity() * std::mem::size_of::<AttrId>()
+            + extras * 2
+    }
+}
+
+/// Text of a single grapheme cluster
+/// Anything up to 4 bytes is kept inline, which covers every single char
+#[derive(Clone, PartialEq, Eq)]
+pub enum CellText {
+    Inline { len: u8, bytes: [u8; 4] },
+    Cluster(Box<String>),
+}
+
+impl CellText {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CellText::Inline { len, bytes } => {
+                std::str::from_utf8(&bytes[..*len as usize]).unwrap_or_default()
+            }
+            CellText::Cluster(text) => text.as_str(),
+        }
+    }
+
+    /// Appends a char, moving to the heap if it no longer fits inline
+    pub fn push(&mut self, c: char) {
+        match self {
+            CellText::Cluster(text) => text.push(c),
+            CellText::Inline { .. } => {
+                let mut text = self.as_str().to_string();
+                text.push(c);
+                *self = CellText::from(text.as_str());
+            }
+        }
+    }
+}
+
+impl From<char> for CellText {
+    fn from(c: char) -> Self {
+        let mut bytes = [0; 4];
+        let len = c.encode_utf8(&mut bytes).len() as u8;
+        CellText::Inline { len, bytes }
+    }
+}
+
+impl From<&str> for CellText {
+    fn from(text: &str) -> Self {
+        if text.len() > 4 {
+            return CellText::Cluster(Box::new(text.to_string()));
+        }
+        let mut bytes = [0; 4];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        CellText::Inline { len: text.len() as u8, bytes }
+    }
+}
+
+impl Deref for CellText {
+    type Target = str;
+
+    fn deref(&self) -> &str { self.as_str() }
+}
+
+impl PartialEq<&str> for CellText {
+    fn eq(&self, other: &&str) -> bool { self.as_str() == *other }
+}
+
+impl Debug for CellText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Display for CellText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 // Change to enum to allow for box drawing etc
 #[derive(Clone, Debug, PartialEq)]
-#[repr(align(8))]
 pub struct Cell {
-    pub text: char,
-    pub attr: CellAttributes,
+    // A single grapheme cluster, empty for the spacer after a wide character
+    pub text: CellText,
+    // How many columns the grapheme takes up, 0 for spacers
+    pub width: u8,
+    // Attributes are looked up in the renderer's AttributeTable
+    pub attr: AttrId,
 }
 
 impl Cell {
-    pub fn new(text: char, attr: CellAttributes) -> Cell { Cell { text, attr } }
+    pub fn new(text: &str, attr: AttrId) -> Cell {
+        let width = Self::display_width(text);
+        Cell { text: CellText::from(text), width, attr }
+    }
 
-    pub fn default() -> Cell {
+    pub fn from_char(c: char, attr: AttrId) -> Cell {
+        let text = CellText::from(c);
+        let width = Self::display_width(&text);
+        Cell { text, width, attr }
+    }
+
+    /// An empty cell, as left behind by erasing
+    pub fn blank(attr: AttrId) -> Cell {
         Cell {
-            text: ' ',
-            attr: CellAttributes::default(),
+            text: CellText::from(' '),
+            width: 1,
+            attr,
+        }
+    }
+
+    /// Placeholder for the second column of a wide character
+    pub fn spacer(attr: AttrId) -> Cell {
+        Cell {
+            text: CellText::from(""),
+            width: 0,
+            attr,
+        }
+    }
+
+    pub fn default() -> Cell { Cell::blank(AttrId::default()) }
+
+    pub fn width(&self) -> usize { self.width as usize }
+
+    pub fn is_spacer(&self) -> bool { self.width == 0 }
+
+    /// If the cell stands in for part of a kitty image
+    pub fn is_placeholder(&self) -> bool { self.text.starts_with(PLACEHOLDER) }
+
+    /// If the char would continue this cell's grapheme cluster instead of starting a new one
+    /// Covers combining marks, zero width joiner sequences, variation selectors and flags
+    pub fn joins(&self, c: char) -> bool {
+        if c.is_ascii() || self.is_spacer() {
+            return false;
         }
+        let mut text = self.text.to_string();
+        text.push(c);
+        text.graphemes(true).count() == 1
     }
 
-    pub fn hash(&self) -> String { format!("{}:{}", self.text, self.attr.hash()) }
+    /// Appends a char to the grapheme cluster, updating the width
+    pub fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.width = Self::display_width(&self.text);
+    }
+
+    // Zero width graphemes without anything to attach to still take up a column
+    fn display_width(text: &str) -> u8 { grapheme_column_width(text, None).clamp(1, 2) as u8 }
 }
 
 #[cfg(test)]
@@ -296,4 +550,90 @@ mod tests {
         attr.set_bold(true);
         assert!(attr.bold());
     }
+
+    #[test]
+    pub fn grapheme_widths() {
+        assert_eq!(Cell::new("a", AttrId::default()).width(), 1);
+        assert_eq!(Cell::new("漢", AttrId::default()).width(), 2);
+        assert_eq!(Cell::new("\u{301}", AttrId::default()).width(), 1);
+    }
+
+    #[test]
+    pub fn joins_clusters() {
+        let mut cell = Cell::new("e", AttrId::default());
+        assert!(cell.joins('\u{301}'));
+        cell.push('\u{301}');
+        assert_eq!(cell.text, "e\u{301}");
+        assert_eq!(cell.width(), 1);
+
+        let flag = Cell::new("\u{1F1EC}", AttrId::default());
+        assert!(flag.joins('\u{1F1E7}'));
+        assert!(!flag.joins('a'));
+    }
+
+    #[test]
+    pub fn inline_text() {
+        let mut text = CellText::from('a');
+        assert!(matches!(text, CellText::Inline { len: 1, .. }));
+        text.push('\u{301}');
+        assert_eq!(text, "a\u{301}");
+        assert!(matches!(text, CellText::Inline { len: 3, .. }));
+
+        let mut family = CellText::from("\u{1F468}");
+        family.push('\u{200D}');
+        assert_eq!(family, "\u{1F468}\u{200D}");
+        assert!(matches!(family, CellText::Cluster(_)));
+        assert_eq!(std::mem::size_of::<Cell>(), 24);
+    }
+
+    #[test]
+    pub fn interned_attributes() {
+        let mut table = AttributeTable::new();
+        let mut bold = CellAttributes::default();
+        bold.set_bold(true);
+
+        assert_eq!(table.intern(&CellAttributes::default()), AttrId(0));
+        let id = table.intern(&bold);
+        assert_eq!(table.intern(&bold.clone()), id);
+        assert!(table.get(id).bold());
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    pub fn interned_links() {
+        let mut table = AttributeTable::new();
+        let linked = |id: &str| {
+            let mut attr = CellAttributes::default();
+            attr.set_hyperlink(Some(Hyperlink::new_with_id("http://example.com", id)));
+            attr
+        };
+
+        let id = table.intern(&linked("a"));
+        assert_eq!(table.intern(&linked("a")), id);
+        assert_ne!(table.intern(&linked("b")), id);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    pub fn semantic_types() {
+        let mut attr = CellAttributes::default();
+        attr.set_bold(true);
+        let types = [
+            SemanticType::Output,
+            SemanticType::Input(Until::LineEnd),
+            SemanticType::Input(Until::SemanticMarker),
+            SemanticType::Prompt(PromptKind::Initial),
+            SemanticType::Prompt(PromptKind::RightSide),
+            SemanticType::Prompt(PromptKind::Continuation),
+            SemanticType::Prompt(PromptKind::Secondary),
+        ];
+        for sem in types {
+            attr.set_sem_type(sem.clone());
+            assert_eq!(attr.semantic_type(), sem);
+            assert!(attr.bold());
+        }
+
+        attr.set_two(14, 15, true, true);
+        assert_eq!(attr.semantic_type(), SemanticType::Output);
+    }
 }
@@ -0,0 +1,72 @@
+/// Character sets that can be designated into G0-G3
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    /// DEC Special Graphics, mostly used for line drawing
+    DecSpecialGraphics,
+    /// British, the same as ascii except for the pound sign
+    Uk,
+}
+
+impl Charset {
+    /// The charset for the final byte of an SCS designation
+    pub fn from_final(control: u8) -> Option<Charset> {
+        match control {
+            b'0' => Some(Charset::DecSpecialGraphics),
+            b'A' => Some(Charset::Uk),
+            b'B' => Some(Charset::Ascii),
+            _ => None,
+        }
+    }
+
+    /// Translates an incoming char through the charset
+    pub fn map(self, c: char) -> char {
+        match self {
+            Charset::Ascii => c,
+            Charset::Uk => match c {
+                '#' => '£',
+                _ => c,
+            },
+            Charset::DecSpecialGraphics => dec_special_graphics(c),
+        }
+    }
+}
+
+fn dec_special_graphics(c: char) -> char {
+    match c {
+        '_' => '\u{a0}',
+        '`' => '◆',
+        'a' => '▒',
+        'b' => '␉',
+        'c' => '␌',
+        'd' => '␍',
+        'e' => '␊',
+        'f' => '°',
+        'g' => '±',
+        'h' => '␤',
+        'i' => '␋',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => c,
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use termwiz::color::SrgbaTuple;
+use termwiz::escape::osc::DynamicColorNumber;
+
+pub type Rgb = [u8; 3];
+
+/// Colours xterm starts the first 16 palette entries with
+const XTERM_ANSI: [Rgb; 16] = [
+    [0, 0, 0],
+    [205, 0, 0],
+    [0, 205, 0],
+    [205, 205, 0],
+    [0, 0, 238],
+    [205, 0, 205],
+    [0, 205, 205],
+    [229, 229, 229],
+    [127, 127, 127],
+    [255, 0, 0],
+    [0, 255, 0],
+    [255, 255, 0],
+    [92, 92, 255],
+    [255, 0, 255],
+    [0, 255, 255],
+    [255, 255, 255],
+];
+
+/// Default colour of a palette entry, the 6x6x6 cube and grey ramp above the first 16
+pub fn xterm_colour(index: u8) -> Rgb {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => XTERM_ANSI[index as usize],
+        16..=231 => {
+            let i = (index - 16) as usize;
+            [LEVELS[i / 36], LEVELS[i / 6 % 6], LEVELS[i % 6]]
+        }
+        _ => [8 + 10 * (index - 232); 3],
+    }
+}
+
+/// Rounds a colour from an escape sequence to 8 bits per channel
+pub fn to_rgb(colour: SrgbaTuple) -> Rgb {
+    let (r, g, b, _) = colour.to_tuple_rgba();
+    [r, g, b].map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// Colour as a reply to a query, xterm scales 8 bits to 16 by repeating them
+pub fn x11_string(rgb: Rgb) -> String {
+    let [r, g, b] = rgb;
+    format!("rgb:{r:02x}{r:02x}/{g:02x}{g:02x}/{b:02x}{b:02x}")
+}
+
+pub fn hex_string(rgb: Rgb) -> String {
+    let [r, g, b] = rgb;
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Colours applications can set and query with OSC 4, 10, 11 and 12
+/// Defaults come from the config palette, overrides last until an application resets them
+#[derive(Debug, Clone)]
+pub struct TerminalColours {
+    pub defaults: [Rgb; 256],
+    pub default_fg: Rgb,
+    pub default_bg: Rgb,
+    pub default_cursor: Rgb,
+
+    pub palette: HashMap<u8, Rgb>,
+    pub fg: Option<Rgb>,
+    pub bg: Option<Rgb>,
+    pub cursor: Option<Rgb>,
+}
+
+impl TerminalColours {
+    pub fn new() -> TerminalColours {
+        TerminalColours {
+            defaults: std::array::from_fn(|i| xterm_colour(i as u8)),
+            default_fg: [255; 3],
+            default_bg: [0; 3],
+            default_cursor: [255; 3],
+            palette: HashMap::new(),
+            fg: None,
+            bg: None,
+            cursor: None,
+        }
+    }
+
+    pub fn palette(&self, index: u8) -> Rgb {
+        self.palette.get(&index).copied().unwrap_or(self.defaults[index as usize])
+    }
+
+    /// Resets palette entries, or the whole palette if none are given
+    pub fn reset_palette(&mut self, indices: &[u8]) {
+        if indices.is_empty() {
+            self.palette.clear();
+        }
+        for index in indices {
+            self.palette.remove(index);
+        }
+    }
+
+    /// The current value of a dynamic colour, None for ones that aren't supported
+    pub fn dynamic(&self, which: DynamicColorNumber) -> Option<Rgb> {
+        match which {
+            DynamicColorNumber::TextForegroundColor => Some(self.fg.unwrap_or(self.default_fg)),
+            DynamicColorNumber::TextBackgroundColor => Some(self.bg.unwrap_or(self.default_bg)),
+            DynamicColorNumber::TextCursorColor => {
+                Some(self.cursor.unwrap_or(self.default_cursor))
+            }
+            _ => None,
+        }
+    }
+
+    /// Overrides a dynamic colour, or resets it with None
+    pub fn set_dynamic(&mut self, which: DynamicColorNumber, colour: Option<Rgb>) {
+        match which {
+            DynamicColorNumber::TextForegroundColor => self.fg = colour,
+            DynamicColorNumber::TextBackgroundColor => self.bg = colour,
+            DynamicColorNumber::TextCursorColor => self.cursor = colour,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn xterm_palette() {
+        assert_eq!(xterm_colour(1), [205, 0, 0]);
+        assert_eq!(xterm_colour(16), [0, 0, 0]);
+        assert_eq!(xterm_colour(196), [255, 0, 0]);
+        assert_eq!(xterm_colour(231), [255, 255, 255]);
+        assert_eq!(xterm_colour(244), [128, 128, 128]);
+        assert_eq!(x11_string([0x1b, 0, 0xff]), "rgb:1b1b/0000/ffff");
+    }
+}
@@ -1,4 +1,7 @@
 use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+use super::state::WorkingDirectory;
 
 #[derive(Debug)]
 pub struct CommandSlicer {
@@ -7,17 +10,35 @@ pub struct CommandSlicer {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Position {
-    x: usize,
-    y: usize,
+    pub x: usize,
+    pub y: usize,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// A command from OSC 133 marks, a prompt followed by its input and output
+#[derive(Debug, Clone, PartialEq)]
 pub struct CommandSlice {
     prompt: Position,
     input: Option<Position>,
     output: Option<Position>,
     end: Option<Position>,
     status: CommandStatus,
+    exit_code: Option<i32>,
+    // Command line, taken from the input when the output starts
+    text: String,
+    // Where the shell was when the command started
+    cwd: Option<WorkingDirectory>,
+    started: Option<SystemTime>,
+    ended: Option<SystemTime>,
+    // Id the shell gave the command, if it sent one
+    aid: Option<String>,
+}
+
+/// Parts of a command that text can be taken from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandZone {
+    Prompt,
+    Input,
+    Output,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -60,26 +81,70 @@ impl CommandSlicer {
             .collect()
     }
 
-    pub fn start_new(&mut self, x: usize, y: usize) {
+    /// The command at an index, as given by get
+    pub fn command(&self, index: usize) -> Option<&CommandSlice> { self.commands.get(index) }
+
+    pub fn start_new(&mut self, x: usize, y: usize, aid: Option<String>) {
         if let Some(command) = self.commands.last_mut() {
             command.end = Some(Position { x, y });
+            command.ended.get_or_insert_with(SystemTime::now);
         }
-        self.commands.push(CommandSlice::new(x, y));
+        let mut command = CommandSlice::new(x, y);
+        command.aid = aid;
+        self.commands.push(command);
     }
 
     pub fn start_input(&mut self, x: usize, y: usize) {
         self.commands.last_mut().unwrap().input = Some(Position { x, y });
     }
 
-    pub fn start_output(&mut self, x: usize, y: usize) {
-        self.commands.last_mut().unwrap().output = Some(Position { x, y });
+    pub fn start_output(&mut self, x: usize, y: usize, cwd: Option<WorkingDirectory>) {
+        let command = self.commands.last_mut().unwrap();
+        command.output = Some(Position { x, y });
+        command.cwd = cwd;
+        command.started = Some(SystemTime::now());
     }
 
+    /// Sets the command line, once it has been read off the screen
+    pub fn set_text(&mut self, text: String) { self.commands.last_mut().unwrap().text = text; }
+
     pub fn set_status(&mut self, status: i32) {
-        self.commands.last_mut().unwrap().status = CommandStatus::from_int(status);
+        let command = self.commands.last_mut().unwrap();
+        command.status = CommandStatus::from_int(status);
+        command.exit_code = Some(status);
+        command.ended = Some(SystemTime::now());
+    }
+
+    /// Keeps the first aid the shell sends for the current command
+    pub fn set_aid(&mut self, aid: Option<String>) {
+        let command = self.commands.last_mut().unwrap();
+        if command.aid.is_none() {
+            command.aid = aid;
+        }
     }
 
     pub fn len(&self) -> usize { self.commands.len() }
+
+    /// Moves every stored position, used after the screen is reflowed
+    pub fn remap(&mut self, map: impl Fn(usize, usize) -> (usize, usize)) {
+        for command in self.commands.iter_mut() {
+            command.remap(&map);
+        }
+    }
+
+    /// Forgets lines dropped from the front of the scrollback
+    /// Commands that were entirely on those lines are removed, the rest move up
+    pub fn drop_lines(&mut self, amount: usize) {
+        self.commands.retain(|command| !command.end.is_some_and(|end| end.y < amount));
+        self.remap(|x, y| (x, y.saturating_sub(amount)));
+    }
+}
+
+impl Position {
+    fn remap(self, map: &impl Fn(usize, usize) -> (usize, usize)) -> Self {
+        let (x, y) = map(self.x, self.y);
+        Position { x, y }
+    }
 }
 
 impl CommandSlice {
@@ -90,6 +155,12 @@ impl CommandSlice {
             output: None,
             end: None,
             status: CommandStatus::None,
+            exit_code: None,
+            text: String::new(),
+            cwd: None,
+            started: None,
+            ended: None,
+            aid: None,
         }
     }
 
@@ -105,6 +176,33 @@ impl CommandSlice {
 
     pub fn get_status(&self) -> CommandStatus { self.status }
 
+    pub fn exit_code(&self) -> Option<i32> { self.exit_code }
+
+    pub fn text(&self) -> &str { &self.text }
+
+    pub fn cwd(&self) -> Option<&WorkingDirectory> { self.cwd.as_ref() }
+
+    pub fn started(&self) -> Option<SystemTime> { self.started }
+
+    pub fn ended(&self) -> Option<SystemTime> { self.ended }
+
+    pub fn aid(&self) -> Option<&str> { self.aid.as_deref() }
+
+    /// How long the command ran, from its output starting to its status or the next prompt
+    pub fn duration(&self) -> Option<Duration> {
+        self.ended?.duration_since(self.started?).ok()
+    }
+
+    /// Where a zone starts and ends, the end is None while the command is still running
+    /// A zone that was skipped, like input for the output before the first prompt, is None
+    pub fn zone(&self, zone: CommandZone) -> Option<(Position, Option<Position>)> {
+        match zone {
+            CommandZone::Prompt => Some((self.prompt, self.input.or(self.output).or(self.end))),
+            CommandZone::Input => Some((self.input?, self.output.or(self.end))),
+            CommandZone::Output => Some((self.output?, self.end)),
+        }
+    }
+
     pub fn range(&self, end: usize) -> Range<usize> {
         match self.end {
             Some(end) => self.prompt.y..end.y,
@@ -113,4 +211,11 @@ impl CommandSlice {
     }
 
     pub fn finished(&self) -> bool { self.end.is_some() }
+
+    fn remap(&mut self, map: &impl Fn(usize, usize) -> (usize, usize)) {
+        self.prompt = self.prompt.remap(map);
+        self.input = self.input.map(|pos| pos.remap(map));
+        self.output = self.output.map(|pos| pos.remap(map));
+        self.end = self.end.map(|pos| pos.remap(map));
+    }
 }
@@ -0,0 +1,147 @@
+// Escape sequence scenarios checked against screen snapshots
+// Loosely follows the screens vttest draws, without needing the GUI
+
+use super::Terminal;
+
+struct Case {
+    name: &'static str,
+    rows: u16,
+    cols: u16,
+    input: &'static [u8],
+    expected: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "cursor movement",
+        rows: 4,
+        cols: 8,
+        input: b"\x1b[2;3Hx\x1b[Ay\x1b[2Bz\x1b[10Dw\x1b[10Cv",
+        expected: "|   y    |\n|  x     |\n|w    z v|\n|        |\ncursor 7,2 pending\n",
+    },
+    Case {
+        name: "autowrap",
+        rows: 3,
+        cols: 5,
+        input: b"abcdefg",
+        expected: "|abcde|+\n|fg   |\n|     |\ncursor 2,1\n",
+    },
+    Case {
+        name: "index and next line",
+        rows: 2,
+        cols: 3,
+        input: b"a\x1bDb\x1bEc",
+        expected: "| b |\n|c  |\ncursor 1,1\n",
+    },
+    Case {
+        name: "reverse index at top",
+        rows: 3,
+        cols: 3,
+        input: b"a\r\nb\x1b[H\x1bMc",
+        expected: "|c  |\n|a  |\n|b  |\ncursor 1,0\n",
+    },
+    Case {
+        name: "erase below",
+        rows: 3,
+        cols: 5,
+        input: b"abcde\r\nfghij\r\nklmno\x1b[2;3H\x1b[J",
+        expected: "|abcde|\n|fg   |\n|     |\ncursor 2,1\n",
+    },
+    Case {
+        name: "erase above",
+        rows: 3,
+        cols: 5,
+        input: b"abcde\r\nfghij\r\nklmno\x1b[2;3H\x1b[1J",
+        expected: "|     |\n|   ij|\n|klmno|\ncursor 2,1\n",
+    },
+    Case {
+        name: "erase characters keeps background",
+        rows: 2,
+        cols: 6,
+        input: b"\x1b[1mab\x1b[0mcd\x1b[1;2H\x1b[41m\x1b[2X",
+        expected: "|a  d  |\n|      |\ncursor 1,0\n\
+                   attr 0-0 0: Default:Default:1\n\
+                   attr 1-2 0: Default:Palette(1):0\n",
+    },
+    Case {
+        name: "insert and delete characters",
+        rows: 1,
+        cols: 6,
+        input: b"abcdef\x1b[1;2H\x1b[2@\x1b[1;5H\x1b[P",
+        expected: "|a  bd |\ncursor 4,0\n",
+    },
+    Case {
+        name: "scroll region",
+        rows: 4,
+        cols: 3,
+        input: b"1\r\n2\r\n3\r\n4\x1b[2;3r\x1b[3;1H\nx",
+        expected: "|1  |\n|3  |\n|x  |\n|4  |\ncursor 1,2\n",
+    },
+    Case {
+        name: "origin mode",
+        rows: 4,
+        cols: 4,
+        input: b"\x1b[2;3r\x1b[?6h\x1b[Hx\x1b[5;1Hy",
+        expected: "|    |\n|x   |\n|y   |\n|    |\ncursor 1,2\n",
+    },
+    Case {
+        name: "insert mode",
+        rows: 1,
+        cols: 5,
+        input: b"abc\r\x1b[4hXY\x1b[4l",
+        expected: "|XYabc|\ncursor 2,0\n",
+    },
+    Case {
+        name: "tab stops",
+        rows: 1,
+        cols: 20,
+        input: b"a\tb\x1b[3g\r\tc",
+        expected: "|a       b          c|\ncursor 19,0 pending\n",
+    },
+    Case {
+        name: "repeat",
+        rows: 1,
+        cols: 6,
+        input: b"ab\x1b[3b",
+        expected: "|abbbb |\ncursor 5,0\n",
+    },
+    Case {
+        name: "wide characters",
+        rows: 2,
+        cols: 5,
+        input: "a漢字x".as_bytes(),
+        expected: "|a漢字|+\n|x    |\ncursor 1,1\n",
+    },
+    Case {
+        name: "dec line drawing",
+        rows: 1,
+        cols: 4,
+        input: b"\x1b(0lqqk",
+        expected: "|┌──┐|\ncursor 3,0 pending\n",
+    },
+    Case {
+        name: "save and restore cursor",
+        rows: 1,
+        cols: 4,
+        input: b"\x1b[1m\x1b7\x1b[0m\x1b[1;3H\x1b8x",
+        expected: "|x   |\ncursor 1,0\nattr 0-0 0: Default:Default:1\n",
+    },
+];
+
+#[test]
+fn conformance() {
+    let mut failures = Vec::new();
+
+    for case in CASES {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(case.rows, case.cols);
+        terminal.feed(case.input);
+
+        let snapshot = terminal.snapshot();
+        if snapshot != case.expected {
+            failures.push(format!("{}\nexpected:\n{}got:\n{}", case.name, case.expected, snapshot));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
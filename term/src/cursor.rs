@@ -1,49 +1,82 @@
 use termwiz::escape::csi::CursorStyle;
 
+use super::cell::CellAttributes;
+use super::charset::Charset;
+
 /// Cursor object to store cursor position and style
 /// Allows for storing, restoring etc with positions as well
 #[derive(Debug)]
 pub struct TerminalCursor {
     pub x: usize,
     pub y: usize,
+    // DECAWM last column flag, the next print wraps before writing
+    pub wrap_pending: bool,
 
-    pub saved_x: usize,
-    pub saved_y: usize,
+    // Primary and alternate screens each get their own saved cursor
+    pub saved: Option<SavedCursor>,
+    pub alt_saved: Option<SavedCursor>,
 
     pub style: CursorStyle,
 }
 
+/// Everything stored by DECSC, to be brought back by DECRC
+#[derive(Debug, Clone, Default)]
+pub struct SavedCursor {
+    pub x: usize,
+    pub y: usize,
+    pub attr: CellAttributes,
+    pub origin_mode: bool,
+    pub charsets: [Charset; 4],
+    pub shift: usize,
+}
+
 impl TerminalCursor {
     pub fn new() -> TerminalCursor {
         TerminalCursor {
             x: 0,
             y: 0,
-            saved_x: 0,
-            saved_y: 0,
+            wrap_pending: false,
+            saved: None,
+            alt_saved: None,
             style: CursorStyle::Default,
         }
     }
 
     /// Sets the cursor position
+    /// Any movement clears a pending wrap
     pub fn set(&mut self, x: u32, y: u32) {
         self.x = x as usize;
         self.y = y as usize;
+        self.wrap_pending = false;
     }
 
     pub fn get(&self) -> (usize, usize) { (self.x, self.y) }
 
-    pub fn set_x(&mut self, x: u32) { self.x = x as usize }
+    pub fn set_x(&mut self, x: u32) {
+        self.x = x as usize;
+        self.wrap_pending = false;
+    }
 
-    pub fn set_y(&mut self, y: u32) { self.y = y as usize }
+    pub fn set_y(&mut self, y: u32) {
+        self.y = y as usize;
+        self.wrap_pending = false;
+    }
 
     /// Shifts the cursor down
-    pub fn shift_down(&mut self, amount: u32) { self.y += amount as usize; }
+    pub fn shift_down(&mut self, amount: u32) {
+        self.y += amount as usize;
+        self.wrap_pending = false;
+    }
 
     /// Shifts the cursor right
-    pub fn shift_right(&mut self, amount: u32) { self.x += amount as usize }
+    pub fn shift_right(&mut self, amount: u32) {
+        self.x += amount as usize;
+        self.wrap_pending = false;
+    }
 
     /// Shifts the cursor up
     pub fn shift_up(&mut self, amount: u32) {
+        self.wrap_pending = false;
         match self.y.checked_sub(amount as usize) {
             Some(n) => self.y = n,
             None => {}
@@ -52,6 +85,7 @@ impl TerminalCursor {
 
     /// Shifts the cursor left
     pub fn shift_left(&mut self, amount: u32) {
+        self.wrap_pending = false;
         match self.x.checked_sub(amount as usize) {
             Some(n) => self.x = n,
             None => {}
@@ -59,4 +93,22 @@ impl TerminalCursor {
     }
 
     pub fn set_style(&mut self, style: CursorStyle) { self.style = style; }
+
+    /// Stores a saved cursor in the slot for the given screen
+    pub fn save(&mut self, alt: bool, saved: SavedCursor) {
+        if alt {
+            self.alt_saved = Some(saved);
+        } else {
+            self.saved = Some(saved);
+        }
+    }
+
+    /// The saved cursor for the given screen
+    pub fn saved(&self, alt: bool) -> Option<&SavedCursor> {
+        if alt {
+            self.alt_saved.as_ref()
+        } else {
+            self.saved.as_ref()
+        }
+    }
 }
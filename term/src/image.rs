@@ -0,0 +1,337 @@
+use std::fmt;
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+
+use base64::Engine;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, ImageFormat, ImageReader, Limits};
+use log::warn;
+use termwiz::escape::{Sixel, SixelData};
+
+/// Decoded image, stored as 8-bit RGBA
+pub struct TerminalImage {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+    // Encoded the first time the GUI asks for it
+    data_url: OnceLock<String>,
+}
+
+impl TerminalImage {
+    pub fn new(width: u32, height: u32, rgba: Vec<u8>) -> TerminalImage {
+        TerminalImage { width, height, rgba, data_url: OnceLock::new() }
+    }
+
+    /// Colour of a pixel, transparent if it is outside the image
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        if x >= self.width || y >= self.height {
+            return [0; 4];
+        }
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        [self.rgba[i], self.rgba[i + 1], self.rgba[i + 2], self.rgba[i + 3]]
+    }
+
+    /// A rectangle cut out of the image, clamped to its size
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> TerminalImage {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+
+        let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+        for row in y..y + height {
+            let start = (row as usize * self.width as usize + x as usize) * 4;
+            rgba.extend_from_slice(&self.rgba[start..start + width as usize * 4]);
+        }
+        TerminalImage::new(width, height, rgba)
+    }
+
+    /// The image as a PNG data url, for use in an img tag
+    pub fn data_url(&self) -> &str {
+        self.data_url.get_or_init(|| {
+            let mut png = Vec::new();
+            let encoder = PngEncoder::new(&mut png);
+            if let Err(err) =
+                encoder.write_image(&self.rgba, self.width, self.height, ExtendedColorType::Rgba8)
+            {
+                warn!("Failed to encode image: {err}");
+            }
+            let encoded = base64::engine::general_purpose::STANDARD.encode(png);
+            format!("data:image/png;base64,{encoded}")
+        })
+    }
+
+    /// Roughly how many bytes the image is using
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<TerminalImage>()
+            + self.rgba.capacity()
+            + self.data_url.get().map_or(0, |url| url.capacity())
+    }
+}
+
+impl fmt::Debug for TerminalImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TerminalImage({}x{})", self.width, self.height)
+    }
+}
+
+/// Decodes an encoded image, guessing the format if it isn't given
+/// Images bigger than MAX_IMAGE_SIZE are refused before anything is allocated for them
+pub fn decode(
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> Result<TerminalImage, image::ImageError> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    match format {
+        Some(format) => reader.set_format(format),
+        None => reader = reader.with_guessed_format()?,
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIZE);
+    limits.max_image_height = Some(MAX_IMAGE_SIZE);
+    reader.limits(limits);
+
+    let image = reader.decode()?.into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(TerminalImage::new(width, height, image.into_raw()))
+}
+
+/// Decodes an image file, taking the first frame of animated images
+pub fn decode_file(data: &[u8]) -> Result<TerminalImage, image::ImageError> { decode(data, None) }
+
+/// Scales a size by to / from, for keeping an aspect ratio
+pub fn scale(size: u32, from: u32, to: u32) -> u32 {
+    (size as u64 * to as u64 / from.max(1) as u64).min(u32::MAX as u64) as u32
+}
+
+/// An image drawn over a block of cells
+/// Anchored to a line in the screen buffer so it scrolls with the text
+#[derive(Debug, Clone)]
+pub struct ImagePlacement {
+    pub image: Arc<TerminalImage>,
+    pub x: usize,
+    pub line: usize,
+    pub cols: usize,
+    pub rows: usize,
+    // Size the image is drawn at in pixels, which may stretch it
+    pub width: u32,
+    pub height: u32,
+    // Pixel offset within the first cell
+    pub offset: (u32, u32),
+    // Kitty image and placement ids, 0 for images from other protocols
+    pub image_id: u32,
+    pub placement_id: u32,
+    pub z_index: i32,
+}
+
+impl ImagePlacement {
+    /// Places an image at its own size, the position is filled in when it is placed
+    pub fn new(image: Arc<TerminalImage>, cols: usize, rows: usize) -> ImagePlacement {
+        ImagePlacement {
+            width: image.width,
+            height: image.height,
+            image,
+            x: 0,
+            line: 0,
+            cols,
+            rows,
+            offset: (0, 0),
+            image_id: 0,
+            placement_id: 0,
+            z_index: 0,
+        }
+    }
+
+    /// If the placement covers any of a block of cells
+    pub fn overlaps(&self, lines: &Range<usize>, columns: &Range<usize>) -> bool {
+        self.line < lines.end
+            && self.line + self.rows > lines.start
+            && self.x < columns.end
+            && self.x + self.cols > columns.start
+    }
+}
+
+/// Largest width or height an image is decoded or drawn at, anything bigger is clipped
+/// Programs choose image sizes, so this keeps one from taking all of memory
+pub const MAX_IMAGE_SIZE: u32 = 4096;
+
+/// Number of colour registers sixel images can define
+const SIXEL_REGISTERS: usize = 256;
+
+/// Colours the VT340 starts with in its first 16 registers
+const VT340_PALETTE: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [51, 51, 204],
+    [204, 33, 33],
+    [51, 204, 51],
+    [204, 51, 204],
+    [51, 204, 204],
+    [204, 204, 51],
+    [135, 135, 135],
+    [66, 66, 66],
+    [84, 84, 153],
+    [153, 66, 66],
+    [84, 153, 84],
+    [153, 84, 153],
+    [84, 153, 153],
+    [153, 153, 84],
+    [204, 204, 204],
+];
+
+/// Converts sixel HSL, where hues start at blue rather than red
+fn hsl_to_rgb(hue: u16, lightness: u8, saturation: u8) -> [u8; 3] {
+    let hue = ((hue as f32 + 240.0) % 360.0) / 60.0;
+    let (l, s) = (lightness as f32 / 100.0, saturation as f32 / 100.0);
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - (hue % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+
+    let (r, g, b) = match hue as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [r, g, b].map(|v| ((v + m) * 255.0).round() as u8)
+}
+
+/// Decodes sixel data into an RGBA image
+/// The size comes from the raster attributes if they were given, clipping anything outside it,
+/// and their aspect ratio stretches each sixel pixel vertically
+/// Either way the size is clipped to MAX_IMAGE_SIZE
+pub fn decode_sixel(sixel: &Sixel) -> TerminalImage {
+    let (width, sixel_height) = sixel.dimensions();
+    let aspect = match sixel.pixel_width {
+        Some(_) if sixel.pad > 0 => (sixel.pan / sixel.pad).clamp(1, MAX_IMAGE_SIZE as i64) as u32,
+        _ => 1,
+    };
+    let width = width.min(MAX_IMAGE_SIZE);
+    let height = sixel_height.saturating_mul(aspect).min(MAX_IMAGE_SIZE);
+
+    let mut palette = vec![[0; 3]; SIXEL_REGISTERS];
+    palette[..VT340_PALETTE.len()].copy_from_slice(&VT340_PALETTE);
+
+    // Unpainted pixels are the first register unless the background is transparent
+    let background = match sixel.background_is_transparent {
+        true => [0; 4],
+        false => [palette[0][0], palette[0][1], palette[0][2], 255],
+    };
+    let mut rgba = background.repeat(width as usize * height as usize);
+
+    let mut colour = palette[0];
+    let (mut x, mut y) = (0u32, 0u32);
+    let mut paint = |x: u32, y: u32, bits: u8, colour: [u8; 3]| {
+        for bit in 0..6 {
+            let pixel_y = y.saturating_add(bit).saturating_mul(aspect);
+            if bits & (1 << bit) == 0 || x >= width || pixel_y >= height {
+                continue;
+            }
+            for row in pixel_y..(pixel_y + aspect).min(height) {
+                let i = (row as usize * width as usize + x as usize) * 4;
+                rgba[i..i + 4].copy_from_slice(&[colour[0], colour[1], colour[2], 255]);
+            }
+        }
+    };
+
+    for data in &sixel.data {
+        match data {
+            SixelData::Data(bits) => {
+                paint(x, y, *bits, colour);
+                x = x.saturating_add(1);
+            }
+            SixelData::Repeat { repeat_count, data } => {
+                // Only the part of a repeat inside the image is painted
+                for repeat_x in x..x.saturating_add(*repeat_count).min(width) {
+                    paint(repeat_x, y, *data, colour);
+                }
+                x = x.saturating_add(*repeat_count);
+            }
+            SixelData::DefineColorMapRGB { color_number, rgb } => {
+                if let Some(register) = palette.get_mut(*color_number as usize) {
+                    let (r, g, b) = rgb.to_tuple_rgb8();
+                    *register = [r, g, b];
+                    colour = *register;
+                }
+            }
+            SixelData::DefineColorMapHSL { color_number, hue_angle, lightness, saturation } => {
+                if let Some(register) = palette.get_mut(*color_number as usize) {
+                    *register = hsl_to_rgb(*hue_angle, *lightness, *saturation);
+                    colour = *register;
+                }
+            }
+            SixelData::SelectColorMapEntry(number) => {
+                colour = palette.get(*number as usize).copied().unwrap_or(palette[0]);
+            }
+            SixelData::CarriageReturn => x = 0,
+            SixelData::NewLine => {
+                x = 0;
+                y = y.saturating_add(6);
+            }
+        }
+    }
+
+    TerminalImage::new(width, height, rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use termwiz::escape::parser::Parser;
+    use termwiz::escape::Action;
+
+    use super::*;
+
+    fn parse_sixel(data: &[u8]) -> Box<Sixel> {
+        let actions = Parser::new().parse_as_vec(data);
+        match actions.into_iter().next() {
+            Some(Action::Sixel(sixel)) => sixel,
+            other => panic!("expected a sixel, got {other:?}"),
+        }
+    }
+
+    #[test]
+    pub fn sixel_palette() {
+        // Red then green in register 1, then a VT340 default in register 2
+        let sixel = parse_sixel(b"\x1bPq\"1;1;3;6#1;2;100;0;0#1~#1;2;0;100;0#1~#2~\x1b\\");
+        let image = decode_sixel(&sixel);
+
+        assert_eq!((image.width, image.height), (3, 6));
+        assert_eq!(image.pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(image.pixel(1, 5), [0, 255, 0, 255]);
+        assert_eq!(image.pixel(2, 3), [204, 33, 33, 255]);
+    }
+
+    #[test]
+    pub fn sixel_transparency_and_rows() {
+        // Only the top pixel of the first row, then a full second row
+        let sixel = parse_sixel(b"\x1bP0;1q#0;2;0;0;100#0@-!2~\x1b\\");
+        let image = decode_sixel(&sixel);
+
+        assert_eq!((image.width, image.height), (2, 12));
+        assert_eq!(image.pixel(0, 0), [0, 0, 255, 255]);
+        assert_eq!(image.pixel(0, 1), [0, 0, 0, 0]);
+        assert_eq!(image.pixel(1, 0), [0, 0, 0, 0]);
+        assert_eq!(image.pixel(1, 11), [0, 0, 255, 255]);
+    }
+
+    #[test]
+    pub fn sixel_oversized_raster() {
+        let sixel = parse_sixel(b"\x1bPq\"1;1;60000;60000#1~\x1b\\");
+        let image = decode_sixel(&sixel);
+        assert_eq!((image.width, image.height), (MAX_IMAGE_SIZE, MAX_IMAGE_SIZE));
+
+        // A huge aspect ratio is clipped the same way
+        let sixel = parse_sixel(b"\x1bPq\"100000;1;2;1#1~~\x1b\\");
+        let image = decode_sixel(&sixel);
+        assert_eq!((image.width, image.height), (2, MAX_IMAGE_SIZE));
+    }
+
+    #[test]
+    pub fn sixel_hsl() {
+        assert_eq!(hsl_to_rgb(0, 50, 100), [0, 0, 255]);
+        assert_eq!(hsl_to_rgb(120, 50, 100), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(240, 50, 100), [0, 255, 0]);
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use flate2::read::ZlibDecoder;
+use termwiz::color::ColorSpec;
+use termwiz::escape::apc::{
+    KittyImageCompression, KittyImageData, KittyImageFormat, KittyImagePlacement,
+    KittyImageTransmit, KittyImageVerbosity,
+};
+
+use super::image::{TerminalImage, MAX_IMAGE_SIZE};
+
+/// Most data a transfer may carry, enough for the largest image as raw RGBA
+pub const MAX_DATA_SIZE: usize = MAX_IMAGE_SIZE as usize * MAX_IMAGE_SIZE as usize * 4;
+
+/// Stands in for a cell of an image, for programs that can only print text
+pub const PLACEHOLDER: char = '\u{10EEEE}';
+
+/// Diacritics that number the rows and columns of placeholder cells, in order
+const DIACRITICS: [u32; 297] = [
+    0x0305, 0x030D, 0x030E, 0x0310, 0x0312, 0x033D, 0x033E, 0x033F, 0x0346, 0x034A, 0x034B,
+    0x034C, 0x0350, 0x0351, 0x0352, 0x0357, 0x035B, 0x0363, 0x0364, 0x0365, 0x0366, 0x0367,
+    0x0368, 0x0369, 0x036A, 0x036B, 0x036C, 0x036D, 0x036E, 0x036F, 0x0483, 0x0484, 0x0485,
+    0x0486, 0x0487, 0x0592, 0x0593, 0x0594, 0x0595, 0x0597, 0x0598, 0x0599, 0x059C, 0x059D,
+    0x059E, 0x059F, 0x05A0, 0x05A1, 0x05A8, 0x05A9, 0x05AB, 0x05AC, 0x05AF, 0x05C4, 0x0610,
+    0x0611, 0x0612, 0x0613, 0x0614, 0x0615, 0x0616, 0x0617, 0x0657, 0x0658, 0x0659, 0x065A,
+    0x065B, 0x065D, 0x065E, 0x06D6, 0x06D7, 0x06D8, 0x06D9, 0x06DA, 0x06DB, 0x06DC, 0x06DF,
+    0x06E0, 0x06E1, 0x06E2, 0x06E4, 0x06E7, 0x06E8, 0x06EB, 0x06EC, 0x0730, 0x0732, 0x0733,
+    0x0735, 0x0736, 0x073A, 0x073D, 0x073F, 0x0740, 0x0741, 0x0743, 0x0745, 0x0747, 0x0749,
+    0x074A, 0x07EB, 0x07EC, 0x07ED, 0x07EE, 0x07EF, 0x07F0, 0x07F1, 0x07F3, 0x0816, 0x0817,
+    0x0818, 0x0819, 0x081B, 0x081C, 0x081D, 0x081E, 0x081F, 0x0820, 0x0821, 0x0822, 0x0823,
+    0x0825, 0x0826, 0x0827, 0x0829, 0x082A, 0x082B, 0x082C, 0x082D, 0x0951, 0x0953, 0x0954,
+    0x0F82, 0x0F83, 0x0F86, 0x0F87, 0x135D, 0x135E, 0x135F, 0x17DD, 0x193A, 0x1A17, 0x1A75,
+    0x1A76, 0x1A77, 0x1A78, 0x1A79, 0x1A7A, 0x1A7B, 0x1A7C, 0x1B6B, 0x1B6D, 0x1B6E, 0x1B6F,
+    0x1B70, 0x1B71, 0x1B72, 0x1B73, 0x1CD0, 0x1CD1, 0x1CD2, 0x1CDA, 0x1CDB, 0x1CE0, 0x1DC0,
+    0x1DC1, 0x1DC3, 0x1DC4, 0x1DC5, 0x1DC6, 0x1DC7, 0x1DC8, 0x1DC9, 0x1DCB, 0x1DCC, 0x1DD1,
+    0x1DD2, 0x1DD3, 0x1DD4, 0x1DD5, 0x1DD6, 0x1DD7, 0x1DD8, 0x1DD9, 0x1DDA, 0x1DDB, 0x1DDC,
+    0x1DDD, 0x1DDE, 0x1DDF, 0x1DE0, 0x1DE1, 0x1DE2, 0x1DE3, 0x1DE4, 0x1DE5, 0x1DE6, 0x1DFE,
+    0x20D0, 0x20D1, 0x20D4, 0x20D5, 0x20D6, 0x20D7, 0x20DB, 0x20DC, 0x20E1, 0x20E7, 0x20E9,
+    0x20F0, 0x2CEF, 0x2CF0, 0x2CF1, 0x2DE0, 0x2DE1, 0x2DE2, 0x2DE3, 0x2DE4, 0x2DE5, 0x2DE6,
+    0x2DE7, 0x2DE8, 0x2DE9, 0x2DEA, 0x2DEB, 0x2DEC, 0x2DED, 0x2DEE, 0x2DEF, 0x2DF0, 0x2DF1,
+    0x2DF2, 0x2DF3, 0x2DF4, 0x2DF5, 0x2DF6, 0x2DF7, 0x2DF8, 0x2DF9, 0x2DFA, 0x2DFB, 0x2DFC,
+    0x2DFD, 0x2DFE, 0x2DFF, 0xA66F, 0xA67C, 0xA67D, 0xA6F0, 0xA6F1, 0xA8E0, 0xA8E1, 0xA8E2,
+    0xA8E3, 0xA8E4, 0xA8E5, 0xA8E6, 0xA8E7, 0xA8E8, 0xA8E9, 0xA8EA, 0xA8EB, 0xA8EC, 0xA8ED,
+    0xA8EE, 0xA8EF, 0xA8F0, 0xA8F1, 0xAAB0, 0xAAB2, 0xAAB3, 0xAAB7, 0xAAB8, 0xAABE, 0xAABF,
+    0xAAC1, 0xFE20, 0xFE21, 0xFE22, 0xFE23, 0xFE24, 0xFE25, 0xFE26, 0x10A0F, 0x10A38, 0x1D185,
+    0x1D186, 0x1D187, 0x1D188, 0x1D189, 0x1D1AA, 0x1D1AB, 0x1D1AC, 0x1D1AD, 0x1D242, 0x1D243,
+    0x1D244,
+];
+
+/// The row or column a placeholder diacritic stands for
+pub fn diacritic_index(c: char) -> Option<usize> { DIACRITICS.binary_search(&(c as u32)).ok() }
+
+/// Image id held in the foreground colour of placeholder cells
+pub fn placeholder_id(colour: ColorSpec) -> Option<u32> {
+    match colour {
+        ColorSpec::Default => None,
+        ColorSpec::PaletteIndex(index) => Some(index as u32),
+        ColorSpec::TrueColor(colour) => {
+            let (r, g, b, _) = colour.to_tuple_rgba();
+            let [r, g, b] = [r, g, b].map(|v| (v * 255.0).round() as u32);
+            Some(r << 16 | g << 8 | b)
+        }
+    }
+}
+
+/// Neighbouring placeholder cells on a line, showing part of an image
+#[derive(Debug, Clone)]
+pub struct PlaceholderRun {
+    pub image: Arc<TerminalImage>,
+    pub image_id: u32,
+    pub x: usize,
+    pub width: usize,
+    // The image cell shown in the first cell of the run
+    pub row: usize,
+    pub col: usize,
+    // Cells the whole image is stretched over
+    pub cols: usize,
+    pub rows: usize,
+}
+
+/// A transmission, which may be split over many chunks
+#[derive(Debug)]
+pub struct Transfer {
+    pub transmit: KittyImageTransmit,
+    pub placement: Option<KittyImagePlacement>,
+    pub verbosity: KittyImageVerbosity,
+    // Queries check that an image could be loaded without keeping it
+    pub query: bool,
+}
+
+#[derive(Debug)]
+pub struct StoredImage {
+    pub image: Arc<TerminalImage>,
+    pub number: Option<u32>,
+    // Cells the image was last placed over, used to lay out placeholders
+    pub cells: Option<(usize, usize)>,
+}
+
+/// Images sent over the kitty graphics protocol, kept until they are deleted
+#[derive(Debug, Default)]
+pub struct ImageStore {
+    images: HashMap<u32, StoredImage>,
+    // Ids picked for images that only gave a number, or no id at all
+    next_id: u32,
+    // A chunked transfer waiting for the rest of its data
+    pending: Option<Transfer>,
+}
+
+impl ImageStore {
+    pub fn new() -> ImageStore { ImageStore::default() }
+
+    /// Collects chunks of a transfer, giving it back once the last chunk arrives
+    /// Only the first chunk carries keys, later ones just add to its data
+    /// A chunk for another image, or more data than an image can use, drops the transfer
+    /// and gives it back with an error to reply with
+    pub fn receive(&mut self, transfer: Transfer) -> Result<Option<Transfer>, (Transfer, String)> {
+        let more = transfer.transmit.more_data_follows;
+        let Some(mut pending) = self.pending.take() else {
+            if more {
+                self.pending = Some(transfer);
+                return Ok(None);
+            }
+            return Ok(Some(transfer));
+        };
+
+        let (id, number) = (transfer.transmit.image_id, transfer.transmit.image_number);
+        if id.is_some_and(|id| pending.transmit.image_id != Some(id))
+            || number.is_some_and(|number| pending.transmit.image_number != Some(number))
+        {
+            return Err((pending, "EINVAL:chunk sent for a different image".into()));
+        }
+
+        if let (KittyImageData::Direct(data), KittyImageData::Direct(chunk)) =
+            (&mut pending.transmit.data, &transfer.transmit.data)
+        {
+            // Base64 takes four bytes for every three
+            if (data.len() + chunk.len()) / 4 * 3 > MAX_DATA_SIZE {
+                return Err((pending, "EFBIG:image data is too large".into()));
+            }
+            data.push_str(chunk);
+        }
+        if more {
+            self.pending = Some(pending);
+            return Ok(None);
+        }
+        Ok(Some(pending))
+    }
+
+    /// Stores an image, replacing any image with the same id
+    /// Returns the id it was stored under
+    pub fn insert(&mut self, image: TerminalImage, id: Option<u32>, number: Option<u32>) -> u32 {
+        let id = id.unwrap_or_else(|| self.unused_id());
+        let image = StoredImage { image: Arc::new(image), number, cells: None };
+        self.images.insert(id, image);
+        id
+    }
+
+    /// Picks an id counting down from the top, away from ids programs choose
+    fn unused_id(&mut self) -> u32 {
+        loop {
+            self.next_id = self.next_id.wrapping_sub(1);
+            if self.next_id != 0 && !self.images.contains_key(&self.next_id) {
+                return self.next_id;
+            }
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&StoredImage> { self.images.get(&id) }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut StoredImage> { self.images.get_mut(&id) }
+
+    /// Id of the newest image sent with a number
+    pub fn find_number(&self, number: u32) -> Option<u32> {
+        self.images
+            .iter()
+            .filter(|(_, image)| image.number == Some(number))
+            .map(|(id, _)| *id)
+            .min_by_key(|id| id.wrapping_sub(self.next_id))
+    }
+
+    pub fn remove(&mut self, id: u32) { self.images.remove(&id); }
+
+    pub fn ids(&self) -> Vec<u32> { self.images.keys().copied().collect() }
+
+    /// Roughly how many bytes the stored images are using
+    pub fn memory_usage(&self) -> usize {
+        self.images.values().map(|image| image.image.memory_usage()).sum()
+    }
+}
+
+/// Reads image data from a file a program named
+/// Only regular files outside /proc, /sys and /dev are read, and never more than MAX_DATA_SIZE,
+/// so devices, pipes and huge files can't hang the terminal or fill memory
+fn read_file(path: &str, offset: Option<u32>, size: Option<u32>) -> Result<Vec<u8>, String> {
+    let path = std::fs::canonicalize(path).map_err(|err| format!("ENOENT:{err}"))?;
+    if ["/proc", "/sys", "/dev"].iter().any(|dir| path.starts_with(dir)) {
+        return Err("EPERM:images can't be read from system files".into());
+    }
+    let metadata = std::fs::metadata(&path).map_err(|err| format!("EBADF:{err}"))?;
+    if !metadata.is_file() {
+        return Err("EINVAL:not a regular file".into());
+    }
+
+    let offset = offset.unwrap_or(0) as u64;
+    let len = size.map_or(metadata.len().saturating_sub(offset), u64::from);
+    if len > MAX_DATA_SIZE as u64 {
+        return Err("EFBIG:image data is too large".into());
+    }
+
+    let mut file = File::open(&path).map_err(|err| format!("EBADF:{err}"))?;
+    file.seek(SeekFrom::Start(offset)).map_err(|err| format!("EBADF:{err}"))?;
+    let mut data = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut data).map_err(|err| format!("EBADF:{err}"))?;
+    if size.is_some() && (data.len() as u64) < len {
+        return Err("ENODATA:the file is shorter than the size given".into());
+    }
+    Ok(data)
+}
+
+/// Deletes a temporary file once it has been read
+/// Like kitty, only files named with tty-graphics-protocol in a temporary directory are deleted
+fn remove_temp_file(path: &str) {
+    let Ok(path) = std::fs::canonicalize(path) else { return };
+    let named = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains("tty-graphics-protocol"));
+    let temp_dirs = [std::env::temp_dir(), PathBuf::from("/tmp"), PathBuf::from("/var/tmp")]
+        .into_iter()
+        .filter_map(|dir| std::fs::canonicalize(dir).ok())
+        .collect::<Vec<_>>();
+    let in_temp = path.parent().is_some_and(|dir| temp_dirs.iter().any(|temp| dir == temp));
+
+    if !named || !in_temp {
+        log::warn!("Not deleting kitty temporary file {path:?}, it isn't named like one");
+        return;
+    }
+    if let Err(err) = std::fs::remove_file(&path) {
+        log::warn!("Failed to delete kitty temporary file {path:?}: {err}");
+    }
+}
+
+/// Reads the data of a transfer, wherever it was sent from
+fn load_data(data: KittyImageData) -> Result<Vec<u8>, String> {
+    match data {
+        KittyImageData::File { path, data_offset, data_size } => {
+            read_file(&path, data_offset, data_size)
+        }
+        KittyImageData::TemporaryFile { path, data_offset, data_size } => {
+            let data = read_file(&path, data_offset, data_size)?;
+            remove_temp_file(&path);
+            Ok(data)
+        }
+        KittyImageData::Direct(data) if data.len() / 4 * 3 > MAX_DATA_SIZE => {
+            Err("EFBIG:image data is too large".into())
+        }
+        KittyImageData::SharedMem { data_size: Some(size), .. }
+            if size as usize > MAX_DATA_SIZE =>
+        {
+            Err("EFBIG:image data is too large".into())
+        }
+        data => data.load_data().map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => format!("ENOENT:{err}"),
+            std::io::ErrorKind::InvalidInput => format!("EINVAL:{err}"),
+            _ => format!("EBADF:{err}"),
+        }),
+    }
+}
+
+/// Reads and decodes the data of a finished transfer
+/// Errors are kitty error codes followed by a message
+pub fn load(transmit: &KittyImageTransmit) -> Result<TerminalImage, String> {
+    let data = load_data(transmit.data.clone())?;
+
+    let data = match transmit.compression {
+        KittyImageCompression::None => data,
+        KittyImageCompression::Deflate => {
+            // Compressed data can inflate to far more than was sent, so it is cut off
+            let mut inflated = Vec::new();
+            ZlibDecoder::new(data.as_slice())
+                .take(MAX_DATA_SIZE as u64 + 1)
+                .read_to_end(&mut inflated)
+                .map_err(|err| format!("EINVAL:failed to inflate: {err}"))?;
+            if inflated.len() > MAX_DATA_SIZE {
+                return Err("EFBIG:image data is too large".into());
+            }
+            inflated
+        }
+    };
+
+    let format = transmit.format.clone().unwrap_or(KittyImageFormat::Rgba);
+    if format == KittyImageFormat::Png {
+        return super::image::decode(&data, Some(image::ImageFormat::Png))
+            .map_err(|err| format!("EBADPNG:{err}"));
+    }
+
+    let (Some(width), Some(height)) = (transmit.width, transmit.height) else {
+        return Err("EINVAL:width and height are needed for raw pixel data".into());
+    };
+    if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+        return Err("EFBIG:the image is too large".into());
+    }
+    let pixels = width as usize * height as usize;
+    let rgba = match format {
+        KittyImageFormat::Rgb if data.len() >= pixels * 3 => {
+            let rgb = data.chunks_exact(3).take(pixels);
+            rgb.flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255]).collect()
+        }
+        KittyImageFormat::Rgba if data.len() >= pixels * 4 => data[..pixels * 4].to_vec(),
+        _ => return Err("ENODATA:not enough pixel data for the image size".into()),
+    };
+    Ok(TerminalImage::new(width, height, rgba))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_transmit(data: KittyImageData) -> KittyImageTransmit {
+        KittyImageTransmit {
+            format: Some(KittyImageFormat::Rgb),
+            data,
+            width: Some(1),
+            height: Some(1),
+            image_id: Some(1),
+            image_number: None,
+            compression: KittyImageCompression::None,
+            more_data_follows: false,
+        }
+    }
+
+    #[test]
+    pub fn file_transfers() {
+        let file = |path: &str| KittyImageData::File {
+            path: path.into(),
+            data_offset: None,
+            data_size: None,
+        };
+        assert!(load(&file_transmit(file("/dev/zero"))).unwrap_err().starts_with("EPERM"));
+        assert!(load(&file_transmit(file("/proc/self/environ"))).is_err());
+        assert!(load(&file_transmit(file("/"))).unwrap_err().starts_with("EINVAL"));
+
+        // Temporary files are only deleted when they are named like one
+        let dir = std::env::temp_dir();
+        for (name, deleted) in [("pretty-tty-graphics-protocol-1", true), ("pretty-kept", false)] {
+            let path = dir.join(name);
+            std::fs::write(&path, [255, 0, 0]).unwrap();
+            let data = KittyImageData::TemporaryFile {
+                path: path.to_string_lossy().into(),
+                data_offset: None,
+                data_size: None,
+            };
+            let image = load(&file_transmit(data)).unwrap();
+            assert_eq!(image.pixel(0, 0), [255, 0, 0, 255]);
+            assert_eq!(path.exists(), !deleted);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    pub fn diacritics() {
+        assert!(DIACRITICS.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(diacritic_index('\u{0305}'), Some(0));
+        assert_eq!(diacritic_index('\u{030D}'), Some(1));
+        assert_eq!(diacritic_index('\u{1D244}'), Some(296));
+        assert_eq!(diacritic_index('a'), None);
+    }
+}
@@ -1,32 +1,61 @@
 pub mod cell;
+pub mod charset;
+pub mod colours;
 pub mod command;
 pub mod cursor;
+pub mod image;
+pub mod kitty;
 pub mod line;
+pub mod links;
 pub mod pty;
 pub mod screen;
+pub mod shell;
 pub mod state;
 pub mod window;
 
+#[cfg(test)]
+mod conformance;
+
 use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
 
-use cell::{Cell, PromptKind, SemanticType, Until};
-use cursor::TerminalCursor;
+use base64::Engine;
+use cell::{AttrId, Cell, CellAttributes, PromptKind, SemanticType, Until};
+use charset::Charset;
+use cursor::{SavedCursor, TerminalCursor};
 use line::Line;
-use log::info;
+use log::{info, warn};
+use num_traits::{FromPrimitive, ToPrimitive};
 use screen::{Screen, TerminalRenderer};
-use state::TerminalState;
-use termwiz::escape::csi::{CsiParam, Cursor, Device, Edit, EraseInDisplay, EraseInLine, Unspecified, CSI};
-use termwiz::escape::osc::{FinalTermSemanticPrompt, ITermProprietary};
+use state::{ScrollRegion, TerminalState, WorkingDirectory};
+use termwiz::escape::csi::{
+    CsiParam, Cursor, DecPrivateMode, DecPrivateModeCode, Device, Edit, EraseInDisplay,
+    EraseInLine, Keyboard, Mode, TabulationClear, TerminalMode, Unspecified, CSI,
+};
+use termwiz::escape::osc::{
+    ChangeColorPair, ColorOrQuery, DynamicColorNumber, FinalTermSemanticPrompt, ITermDimension,
+    ITermFileData, ITermProprietary, Selection,
+};
+use termwiz::escape::parser::Parser;
+use termwiz::escape::apc::{KittyImageDelete, KittyImagePlacement, KittyImageVerbosity};
 use termwiz::escape::{Action, ControlCode, Esc, KittyImage, OperatingSystemCommand, Sixel};
-use window::WindowHandler;
+use termwiz::hyperlink::{Hyperlink, Rule};
+use window::{Clipboard, WindowHandler};
 
-use self::command::CommandSlicer;
+use self::colours::{to_rgb, x11_string, TerminalColours};
+use self::command::{CommandSlicer, CommandZone};
+use self::image::{scale, ImagePlacement};
+use self::kitty::{ImageStore, PlaceholderRun, Transfer};
+use self::links::{link_allowed, resolve_link, MAX_LINK_ROWS};
 
 /// Main terminal controller
 /// Holds a lot of sub-objects
 pub struct Terminal {
     pub rows: u16,
     pub cols: u16,
+    // Pixel size of a cell, for working out how many cells an image covers
+    pub cell_size: (u32, u32),
 
     pub renderer: TerminalRenderer,
     pub state: TerminalState,
@@ -35,6 +64,22 @@ pub struct Terminal {
     pub user_vars: HashMap<String, String>,
     pub window: Box<dyn WindowHandler>,
     pub marks: Vec<(usize, usize)>,
+    // Images sent with the kitty graphics protocol, shared by both screens
+    pub images: ImageStore,
+    // Palette and default colours, with any overrides applications have set
+    pub colours: TerminalColours,
+    // Turn matching text in finished lines into implicit hyperlinks
+    pub link_rules: Vec<Rule>,
+    // Schemes implicit links may have, besides file links on this machine
+    pub link_schemes: Vec<String>,
+    // Keeps partial escape sequences between calls to feed
+    parser: Parser,
+    // Last printed character, used by REP
+    last_char: Option<char>,
+    // Where the last grapheme was printed, so combining characters can join it
+    last_print: Option<(usize, usize)>,
+    // Replies to queries, waiting to be written back to the pty
+    responses: Vec<String>,
 
     pub title_stack: Vec<String>,
     pub title: String,
@@ -44,16 +89,28 @@ impl Terminal {
     // TODO: pty box
     // Creates a Terminal Object with a Window Handler
     pub fn setup<T: WindowHandler + 'static>(window: Box<T>) -> anyhow::Result<Terminal> {
+        let mut state = TerminalState::new();
+        state.reset_tab_stops(80);
+
         Ok(Terminal {
             rows: 24,
             cols: 80,
+            cell_size: (8, 16),
             renderer: TerminalRenderer::new(24, 80),
-            state: TerminalState::new(),
+            state,
             cursor: TerminalCursor::new(),
             commands: CommandSlicer::new(),
             user_vars: HashMap::new(),
             window,
             marks: Vec::new(),
+            images: ImageStore::new(),
+            colours: TerminalColours::new(),
+            link_rules: Vec::new(),
+            link_schemes: Vec::new(),
+            parser: Parser::new(),
+            last_char: None,
+            last_print: None,
+            responses: Vec::new(),
             title_stack: Vec::new(),
             title: "PreTTY".into(),
         })
@@ -84,8 +141,72 @@ impl Terminal {
         for action in actions {
             self.handle_action(action);
         }
+        // Attributes of lines that were trimmed, cleared or overwritten are freed here
+        self.renderer.compact_attrs();
+    }
+
+    /// Parses raw pty output and handles it
+    /// Sequences split across calls are completed by the next call
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let actions = self.parser.parse_as_vec(bytes);
+        self.handle_actions(actions);
+    }
+
+    /// Dumps the visible screen, cursor and any non-default attributes as text
+    /// Rows are wrapped in pipes with a trailing + if they soft wrap,
+    /// and attributes are listed as runs of cells
+    pub fn snapshot(&self) -> String {
+        let (rows, cols) = (self.rows as usize, self.cols as usize);
+        let screen = self.screen();
+        let mut text = String::new();
+        let mut attrs = String::new();
+
+        for y in 0..rows {
+            let line = screen.line(screen.phys_line(y));
+            let mut row = String::new();
+
+            if let Some(line) = line {
+                for cell in line.iter().take(cols).filter(|cell| !cell.is_spacer()) {
+                    row.push_str(&cell.text);
+                }
+
+                let mut x = 0;
+                for (attr, run) in line.runs() {
+                    let end = (x + run.len()).min(cols);
+                    if attr != AttrId::default() && x < end {
+                        let attr = self.renderer.attrs.get(attr).describe();
+                        attrs.push_str(&format!("attr {x}-{} {y}: {attr}\n", end - 1));
+                    }
+                    x += run.len();
+                }
+            }
+
+            let used = line.map_or(0, |line| line.len().min(cols));
+            row.extend(std::iter::repeat(' ').take(cols - used));
+            let wrapped = line.is_some_and(|line| line.wrapped);
+            text.push_str(&format!("|{row}|{}\n", if wrapped { "+" } else { "" }));
+        }
+
+        let (x, y) = self.cursor.get();
+        let pending = if self.cursor.wrap_pending { " pending" } else { "" };
+        text.push_str(&format!("cursor {x},{y}{pending}\n"));
+        text + &attrs
     }
 
+    /// Attributes of a cell on either screen
+    pub fn cell_attr(&self, cell: &Cell) -> &CellAttributes { self.renderer.attrs.get(cell.attr) }
+
+    /// Roughly how many bytes both screens and their attributes are using
+    pub fn memory_usage(&self) -> usize {
+        self.renderer.memory_usage() + self.images.memory_usage()
+    }
+
+    /// Takes every reply that needs to be written back to the pty
+    pub fn drain_responses(&mut self) -> Vec<String> { std::mem::take(&mut self.responses) }
+
+    /// Queues a reply to be written back to the pty
+    fn respond(&mut self, response: String) { self.responses.push(response) }
+
     /// Immutable reference to the current screen object
     pub fn screen(&self) -> &Screen { self.renderer.get_screen(self.state.alt_screen) }
 
@@ -96,8 +217,14 @@ impl Terminal {
     /// Needed for accessing cells from a screen object with scrollback
     pub fn cursor_pos(&self) -> (usize, usize) { (self.cursor.x, self.phys_cursor_y()) }
 
+    /// Generation of the active screen, increased whenever a line changes
+    pub fn generation(&self) -> u64 { self.screen().generation() }
+
+    /// Lines of the active screen changed since a generation, for partial rerenders
+    pub fn damaged_lines(&self, since: u64) -> Vec<usize> { self.screen().damaged_lines(since) }
+
     pub fn current_line(&mut self) -> &mut Line {
-        let line_index = self.phys_cursor_y();
+        let line_index = self.cursor.y;
         self.mut_screen().mut_line(line_index)
     }
 
@@ -107,18 +234,187 @@ impl Terminal {
     /// Sets how large the terminal believes it is
     /// Only needed if you need TUI apps to work
     pub fn resize(&mut self, rows: u16, cols: u16) {
-        self.rows = rows.into();
-        self.cols = cols.into()
+        let old_cols = self.cols as usize;
+        self.rows = rows;
+        self.cols = cols;
+        let (rows, cols) = (rows as usize, cols as usize);
+
+        if cols != old_cols && cols > 0 {
+            self.reflow();
+        }
+
+        self.renderer.screen.set_size(rows, cols);
+        self.renderer.alt_screen.set_size(rows, cols);
+        self.state.scroll_region = None;
+        self.state.reset_tab_stops(cols);
+
+        if rows > 0 && self.cursor.y >= rows {
+            let overflow = self.cursor.y + 1 - rows;
+            self.mut_screen().shift_visible(overflow);
+            self.cursor.y = rows - 1;
+        }
+        if self.cursor.x >= cols {
+            self.cursor.set_x(cols.saturating_sub(1) as u32);
+        }
+        self.trim_scrollback();
+    }
+
+    /// Sets the pixel size of a cell, as measured by the window
+    pub fn set_cell_size(&mut self, width: u32, height: u32) {
+        self.cell_size = (width.max(1), height.max(1));
+    }
+
+    /// Sets how many lines of scrollback the primary screen keeps
+    pub fn set_max_scrollback(&mut self, max_scrollback: usize) {
+        self.renderer.screen.set_max_scrollback(max_scrollback);
+        self.trim_scrollback();
+    }
+
+    /// Drops scrollback over the limit from the primary screen
+    fn trim_scrollback(&mut self) {
+        let dropped = self.renderer.screen.trim_scrollback();
+        self.drop_lines(dropped);
+    }
+
+    /// Clears the primary screen's scrollback, keeping what is visible
+    pub fn clear_scrollback(&mut self) {
+        let dropped = self.renderer.screen.clear_scrollback();
+        self.drop_lines(dropped);
+    }
+
+    /// Moves commands and marks up after lines fall off the front of the scrollback
+    fn drop_lines(&mut self, amount: usize) {
+        if amount == 0 {
+            return;
+        }
+        self.commands.drop_lines(amount);
+        self.marks.retain(|mark| mark.1 >= amount);
+        for mark in self.marks.iter_mut() {
+            mark.1 -= amount;
+        }
+    }
+
+    /// Rewraps the primary screen to the current width
+    /// Keeps the cursor on the same logical character, as well as commands and marks
+    fn reflow(&mut self) {
+        let cols = self.cols as usize;
+        let alt = self.state.alt_screen;
+        // A pending wrap sits just past the last column
+        let x = self.cursor.x + self.cursor.wrap_pending as usize;
+        let y = self.renderer.screen.phys_line(self.cursor.y);
+        let anchor = if alt { (0, self.renderer.screen.visible_start()) } else { (x, y) };
+
+        let map = self.renderer.screen.reflow(cols, anchor);
+
+        if !alt {
+            let (x, y) = map.map_cursor(x, y);
+            let last = cols.saturating_sub(1);
+            let y = y.saturating_sub(self.renderer.screen.visible_start());
+            self.cursor.set(x.min(last) as u32, y as u32);
+            self.cursor.wrap_pending = x > last;
+        }
+        self.commands.remap(|x, y| map.map(x, y));
+        for mark in self.marks.iter_mut() {
+            *mark = map.map(mark.0, mark.1);
+        }
+    }
+
+    /// Top and bottom of the current scroll region, zero-based and inclusive
+    pub fn scroll_region(&self) -> (usize, usize) {
+        let last = (self.rows as usize).saturating_sub(1);
+        match self.state.scroll_region {
+            Some(ScrollRegion { top, bottom }) => (top.min(last), bottom.min(last)),
+            None => (0, last),
+        }
+    }
+
+    /// Sets the scroll region with DECSTBM, resetting it if it covers the screen
+    fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        let last = (self.rows as usize).saturating_sub(1);
+        let bottom = bottom.min(last);
+        if top >= bottom {
+            return;
+        }
+
+        self.state.scroll_region = if top == 0 && bottom == last {
+            None
+        } else {
+            Some(ScrollRegion { top, bottom })
+        };
+        self.goto(0, 0);
+    }
+
+    fn origin_mode(&self) -> bool { self.state.dec_mode(DecPrivateModeCode::OriginMode) }
+
+    /// DECOM, switching it either way homes the cursor
+    fn set_origin_mode(&mut self, active: bool) {
+        self.state.set_dec_private_mode(DecPrivateMode::Code(DecPrivateModeCode::OriginMode), active);
+        self.goto(0, 0);
+    }
+
+    /// Moves the cursor to a zero-based position, clamped to the screen
+    /// In origin mode the line is relative to the scroll region and can't leave it
+    fn goto(&mut self, x: usize, y: usize) {
+        let (top, bottom) = match self.origin_mode() {
+            true => self.scroll_region(),
+            false => (0, (self.rows as usize).saturating_sub(1)),
+        };
+        let x = x.min((self.cols as usize).saturating_sub(1));
+        let y = (top + y).min(bottom);
+        self.cursor.set(x as u32, y as u32);
+    }
+
+    /// Moves the cursor up, stopping at the top margin if it starts inside the region
+    fn cursor_up(&mut self, amount: u32) {
+        let (top, _) = self.scroll_region();
+        let limit = if self.cursor.y >= top { top } else { 0 };
+        let y = self.cursor.y.saturating_sub(amount as usize).max(limit);
+        self.cursor.set_y(y as u32);
+    }
+
+    /// Moves the cursor down, stopping at the bottom margin if it starts inside the region
+    fn cursor_down(&mut self, amount: u32) {
+        let (_, bottom) = self.scroll_region();
+        let last = (self.rows as usize).saturating_sub(1);
+        let limit = if self.cursor.y <= bottom { bottom } else { last };
+        let y = (self.cursor.y + amount as usize).min(limit);
+        self.cursor.set_y(y as u32);
+    }
+
+    /// Moves the cursor right, stopping at the last column
+    fn cursor_right(&mut self, amount: u32) {
+        let last = (self.cols as usize).saturating_sub(1);
+        let x = (self.cursor.x + amount as usize).min(last);
+        self.cursor.set_x(x as u32);
+    }
+
+    /// Scrolls the scroll region up, making space at the bottom margin
+    fn scroll_up(&mut self, amount: usize) {
+        let (top, bottom) = self.scroll_region();
+        let blank = self.empty_cell();
+        self.mut_screen().scroll_up(top, bottom, amount, blank);
+        self.trim_scrollback();
+    }
+
+    /// Scrolls the scroll region down, making space at the top margin
+    fn scroll_down(&mut self, amount: usize) {
+        let (top, bottom) = self.scroll_region();
+        let blank = self.empty_cell();
+        self.mut_screen().scroll_down(top, bottom, amount, blank);
     }
 
     fn handle_control(&mut self, control_code: ControlCode) {
         match control_code {
-            ControlCode::LineFeed => self.new_line(),
+            ControlCode::LineFeed | ControlCode::VerticalTab | ControlCode::FormFeed => {
+                self.line_feed()
+            }
             ControlCode::CarriageReturn => self.cursor.set_x(0),
             ControlCode::Backspace => self.backspace(),
             ControlCode::Null => info!("Read NULL char"),
             ControlCode::Bell => self.window.bell(),
-            ControlCode::HorizontalTab => self.print_str("    ".to_string()),
+            ControlCode::HorizontalTab => self.forward_tab(1),
+            ControlCode::ShiftIn => self.state.shift = 0,
+            ControlCode::ShiftOut => self.state.shift = 1,
             _ => info!("Unimplemented: {control_code:?}"),
         }
     }
@@ -126,10 +422,13 @@ impl Terminal {
     fn handle_csi(&mut self, csi: CSI) {
         match csi {
             CSI::Sgr(sgr) => self.renderer.handle_sgr(sgr),
-            CSI::Mode(mode) => self.state.handle_state(mode),
+            CSI::Mode(mode) => self.handle_mode(mode),
             CSI::Cursor(cursor) => self.handle_cursor(cursor),
             CSI::Edit(edit) => self.handle_edit(edit),
             CSI::Device(device) => self.handle_device(device),
+            CSI::Keyboard(Keyboard::QueryKittySupport) => {
+                self.respond(format!("\x1b[?{}u", self.state.kitty_state))
+            }
             CSI::Keyboard(keyboard) => self.state.handle_kitty_keyboard(keyboard),
             CSI::Mouse(_) => {} // These are input only
             CSI::Window(command) => self.window.csi_window(command),
@@ -140,6 +439,84 @@ impl Terminal {
         }
     }
 
+    /// Handles modes that need more than the terminal state
+    fn handle_mode(&mut self, mode: Mode) {
+        use DecPrivateModeCode::{
+            ClearAndEnableAlternateScreen, EnableAlternateScreen, OptEnableAlternateScreen,
+            OriginMode, SaveCursor,
+        };
+        match mode {
+            Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                code @ (EnableAlternateScreen | OptEnableAlternateScreen | ClearAndEnableAlternateScreen),
+            )) => self.set_alt_screen(code, true),
+            Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                code @ (EnableAlternateScreen | OptEnableAlternateScreen | ClearAndEnableAlternateScreen),
+            )) => self.set_alt_screen(code, false),
+            Mode::SetDecPrivateMode(DecPrivateMode::Code(SaveCursor)) => self.save_cursor(),
+            Mode::ResetDecPrivateMode(DecPrivateMode::Code(SaveCursor)) => self.restore_cursor(),
+            Mode::SetDecPrivateMode(DecPrivateMode::Code(OriginMode)) => self.set_origin_mode(true),
+            Mode::ResetDecPrivateMode(DecPrivateMode::Code(OriginMode)) => {
+                self.set_origin_mode(false)
+            }
+            Mode::QueryDecPrivateMode(mode) => self.report_dec_mode(mode),
+            Mode::QueryMode(mode) => self.report_mode(mode),
+            _ => self.state.handle_state(mode),
+        }
+    }
+
+    /// Switches between the primary and alternate screens
+    /// 47 only switches, 1047 clears the alternate screen on the way out,
+    /// and 1049 saves the cursor then clears the alternate screen on the way in
+    fn set_alt_screen(&mut self, code: DecPrivateModeCode, active: bool) {
+        use DecPrivateModeCode::*;
+        if active == self.state.alt_screen {
+            return;
+        }
+
+        if active {
+            if code == ClearAndEnableAlternateScreen {
+                self.save_cursor();
+                self.renderer.alt_screen.clear();
+            }
+            // The primary screen may have been resized while the alternate one was unused
+            self.renderer.alt_screen.set_size(self.rows as usize, self.cols as usize);
+        } else if code == OptEnableAlternateScreen {
+            self.renderer.alt_screen.clear();
+        }
+
+        self.state.alt_screen = active;
+        self.last_print = None;
+
+        if !active && code == ClearAndEnableAlternateScreen {
+            self.restore_cursor();
+        }
+    }
+
+    /// DECRQM reply for a dec private mode
+    /// 0 is an unrecognised mode, 1 is set and 2 is reset
+    fn report_dec_mode(&mut self, mode: DecPrivateMode) {
+        let (code, status) = match mode {
+            DecPrivateMode::Code(code) => {
+                let status = if self.state.dec_mode_set(code.clone()) { 1 } else { 2 };
+                (code.to_u16().unwrap(), status)
+            }
+            DecPrivateMode::Unspecified(code) => (code, 0),
+        };
+        self.respond(format!("\x1b[?{code};{status}$y"));
+    }
+
+    /// DECRQM reply for an ANSI terminal mode
+    fn report_mode(&mut self, mode: TerminalMode) {
+        let (code, status) = match mode {
+            TerminalMode::Code(code) => {
+                let code = code.to_u16().unwrap();
+                (code, if self.state.mode(code) { 1 } else { 2 })
+            }
+            TerminalMode::Unspecified(code) => (code, 0),
+        };
+        self.respond(format!("\x1b[{code};{status}$y"));
+    }
+
     fn handle_csi_unspecified(&mut self, unspecified: Box<Unspecified>) {
         if unspecified.control != 't' || unspecified.parameters_truncated {
             info!("Unknown CSI {unspecified:?}");
@@ -158,9 +535,6 @@ impl Terminal {
         }
     }
 
-    fn kitty_image(&mut self, _image: Box<KittyImage>) {
-        todo!("Kitty Image");
-    }
 
     /// Handles any Esc codes
     fn handle_esc(&mut self, esc: Esc) {
@@ -172,7 +546,7 @@ impl Terminal {
                 intermediate,
                 control,
             } => {
-                info!("ESC Unknown {:?} {:?}", intermediate, control);
+                self.handle_esc_unspecified(intermediate, control);
                 return;
             }
         };
@@ -185,62 +559,319 @@ impl Terminal {
             DecDoubleHeightBottomHalfLine => self.current_line().set_double(true),
             DecNormalKeyPad => self.state.alt_keypad = false,
             DecApplicationKeyPad => self.state.alt_keypad = true,
-            AsciiCharacterSetG0 => {}
+            Index => self.new_line(),
+            NextLine => {
+                self.new_line();
+                self.cursor.set_x(0);
+            }
+            ReverseIndex => self.reverse_index(),
+            HorizontalTabSet => self.state.set_tab_stop(self.cursor.x),
+            DecSaveCursorPosition => self.save_cursor(),
+            DecRestoreCursorPosition => self.restore_cursor(),
+            AsciiCharacterSetG0 => self.state.charsets[0] = Charset::Ascii,
+            DecLineDrawingG0 => self.state.charsets[0] = Charset::DecSpecialGraphics,
+            UkCharacterSetG0 => self.state.charsets[0] = Charset::Uk,
+            AsciiCharacterSetG1 => self.state.charsets[1] = Charset::Ascii,
+            DecLineDrawingG1 => self.state.charsets[1] = Charset::DecSpecialGraphics,
+            UkCharacterSetG1 => self.state.charsets[1] = Charset::Uk,
+            SingleShiftG2 => self.state.single_shift = Some(2),
+            SingleShiftG3 => self.state.single_shift = Some(3),
             _ => info!("ESC {:?}", code),
         }
     }
 
+    /// Esc codes termwiz doesn't know about
+    fn handle_esc_unspecified(&mut self, intermediate: Option<u8>, control: u8) {
+        // G2 and G3 designations
+        let designation = match intermediate {
+            Some(b'*') => Some(2),
+            Some(b'+') => Some(3),
+            _ => None,
+        };
+        if let (Some(index), Some(charset)) = (designation, Charset::from_final(control)) {
+            self.state.charsets[index] = charset;
+            return;
+        }
+
+        match (intermediate, control) {
+            // LS2 and LS3
+            (None, b'n') => self.state.shift = 2,
+            (None, b'o') => self.state.shift = 3,
+            _ => info!("ESC Unknown {:?} {:?}", intermediate, control),
+        }
+    }
+
     /// "Renders" a sixel image
     /// Really just stores it in a state for the webview to render
-    fn handle_sixel(&mut self, sixel: Box<Sixel>) { info!("Sixel Image {sixel:?}") }
+    fn handle_sixel(&mut self, sixel: Box<Sixel>) {
+        let image = self::image::decode_sixel(&sixel);
+        let (cols, rows) = self.image_cells(image.width, image.height);
+        let mut placement = ImagePlacement::new(Arc::new(image), cols, rows);
+
+        // With sixel display mode the image goes in the top left corner and nothing scrolls
+        if self.state.dec_mode(DecPrivateModeCode::SixelDisplayMode) {
+            placement.line = self.screen().phys_line(0);
+            self.mut_screen().place_image(placement);
+            return;
+        }
+
+        self.place_image(placement);
+    }
+
+    /// Number of cells an image covers, rounding up
+    fn image_cells(&self, width: u32, height: u32) -> (usize, usize) {
+        let (width, height) = self.clamp_image_size(width, height);
+        let (cell_width, cell_height) = self.cell_size;
+        let cols = width.div_ceil(cell_width).max(1) as usize;
+        let rows = height.div_ceil(cell_height).max(1) as usize;
+        (cols, rows)
+    }
+
+    /// Limits the size an image is drawn at, as programs can ask for any size
+    /// Images can be a few screens wide, but no taller than the screen and its scrollback
+    fn clamp_image_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (cell_width, cell_height) = self.cell_size;
+        let rows = self.rows as usize + self.renderer.screen.max_scrollback();
+        let rows = u32::try_from(rows).unwrap_or(u32::MAX);
+        let max_width = (self.cols as u32).saturating_mul(cell_width).saturating_mul(4);
+        (width.min(max_width), height.min(rows.saturating_mul(cell_height)))
+    }
+
+    /// Places an image at the cursor, scrolling to make room for it
+    /// The cursor is left on the last line of the image, in the same column
+    fn place_image(&mut self, mut placement: ImagePlacement) {
+        placement.x = self.cursor.x;
+        for _ in 1..placement.rows {
+            self.new_line();
+        }
+
+        placement.line = self.phys_cursor_y().saturating_sub(placement.rows - 1);
+        self.mut_screen().place_image(placement);
+    }
 
     /// Handles cursor movements, etc
     // Really need to move this to the cursor object
     fn handle_cursor(&mut self, cursor: Cursor) {
         use Cursor::*;
         match cursor {
-            Left(amount) => self.cursor.shift_left(amount),
-            Down(amount) | NextLine(amount) => self.cursor.shift_down(amount),
-            Right(amount) => self.cursor.shift_right(amount),
-            Up(amount) | PrecedingLine(amount) => self.cursor.shift_right(amount),
-            Position { line, col } => self
-                .cursor
-                .set(col.as_one_based() - 1, line.as_one_based() - 1),
+            Left(amount) | CharacterPositionBackward(amount) => self.cursor.shift_left(amount),
+            Right(amount) | CharacterPositionForward(amount) => self.cursor_right(amount),
+            Down(amount) | LinePositionForward(amount) => self.cursor_down(amount),
+            Up(amount) | LinePositionBackward(amount) => self.cursor_up(amount),
+            NextLine(amount) => {
+                self.cursor_down(amount);
+                self.cursor.set_x(0);
+            }
+            PrecedingLine(amount) => {
+                self.cursor_up(amount);
+                self.cursor.set_x(0);
+            }
+            Position { line, col } | CharacterAndLinePosition { line, col } => {
+                self.goto(col.as_zero_based() as usize, line.as_zero_based() as usize)
+            }
+            CharacterAbsolute(col) | CharacterPositionAbsolute(col) => {
+                let last = (self.cols as usize).saturating_sub(1);
+                self.cursor.set_x((col.as_zero_based() as usize).min(last) as u32);
+            }
+            // VPA is one-based, but termwiz doesn't wrap it
+            LinePositionAbsolute(line) => self.goto(self.cursor.x, line.saturating_sub(1) as usize),
             CursorStyle(style) => self.cursor.set_style(style),
+            RequestActivePositionReport => self.report_cursor_position(),
+            SaveCursor => self.save_cursor(),
+            RestoreCursor => self.restore_cursor(),
+            ForwardTabulation(amount) => self.forward_tab(amount),
+            BackwardTabulation(amount) => self.backward_tab(amount),
+            TabulationClear(clear) => self.clear_tabs(clear),
+            SetTopAndBottomMargins { top, bottom } => {
+                self.set_scroll_region(top.as_zero_based() as usize, bottom.as_zero_based() as usize)
+            }
             _ => info!("Cursor {cursor:?}"),
         }
     }
 
+    /// DECSC, saves the cursor position and attributes for the current screen
+    fn save_cursor(&mut self) {
+        let saved = SavedCursor {
+            x: self.cursor.x,
+            y: self.cursor.y,
+            attr: self.renderer.attr().clone(),
+            origin_mode: self.state.dec_mode(DecPrivateModeCode::OriginMode),
+            charsets: self.state.charsets,
+            shift: self.state.shift,
+        };
+        self.cursor.save(self.state.alt_screen, saved);
+    }
+
+    /// DECRC, restores the saved cursor for the current screen
+    /// Without a saved cursor this moves home and resets attributes, like xterm
+    fn restore_cursor(&mut self) {
+        let saved = self.cursor.saved(self.state.alt_screen).cloned().unwrap_or_default();
+
+        let x = saved.x.min((self.cols as usize).saturating_sub(1));
+        let y = saved.y.min((self.rows as usize).saturating_sub(1));
+        self.cursor.set(x as u32, y as u32);
+        *self.renderer.attr_mut() = saved.attr;
+        self.state.charsets = saved.charsets;
+        self.state.shift = saved.shift;
+        self.state.set_dec_private_mode(
+            DecPrivateMode::Code(DecPrivateModeCode::OriginMode),
+            saved.origin_mode,
+        );
+    }
+
+    /// Moves the cursor to the next tab stop, stopping at the last column
+    fn forward_tab(&mut self, amount: u32) {
+        let last = (self.cols as usize).saturating_sub(1);
+        for _ in 0..amount {
+            let x = self.state.next_tab_stop(self.cursor.x).unwrap_or(last).min(last);
+            self.cursor.set_x(x as u32);
+        }
+    }
+
+    /// Moves the cursor to the previous tab stop, stopping at the first column
+    fn backward_tab(&mut self, amount: u32) {
+        for _ in 0..amount {
+            let x = self.state.prev_tab_stop(self.cursor.x).unwrap_or(0);
+            self.cursor.set_x(x as u32);
+        }
+    }
+
+    fn clear_tabs(&mut self, clear: TabulationClear) {
+        use TabulationClear::*;
+        match clear {
+            ClearCharacterTabStopAtActivePosition => self.state.clear_tab_stop(self.cursor.x),
+            ClearCharacterTabStopsAtActiveLine | ClearAllCharacterTabStops | ClearAllTabStops => {
+                self.state.clear_tab_stops()
+            }
+            // Line tab stops aren't supported
+            ClearLineTabStopAtActiveLine | ClearAllLineTabStops => {}
+        }
+    }
+
     /// Backspaces at the terminal cursor position
-    fn backspace(&mut self) { self.cursor.x -= 1; }
+    fn backspace(&mut self) { self.cursor.shift_left(1); }
+
+    /// LF, VT and FF, which also return the carriage in LNM
+    fn line_feed(&mut self) {
+        self.detect_links();
+        // Input marked with OSC 133 I only lasts until the end of its line
+        if self.renderer.attr().semantic_type() == SemanticType::Input(Until::LineEnd) {
+            self.renderer.attr_mut().set_sem_type(SemanticType::Output);
+        }
+        self.new_line();
+        if self.state.newline_mode() {
+            self.cursor.set_x(0);
+        }
+    }
 
     // Performs a new line at the terminal cursor position
+    // Scrolls the scroll region if the cursor is on the bottom margin
     fn new_line(&mut self) {
-        self.cursor.shift_down(1);
-        if self.cursor.y == self.rows as usize {
-            self.cursor.y = self.rows as usize - 1;
-            self.mut_screen().scrollback();
+        let (_, bottom) = self.scroll_region();
+        if self.cursor.y == bottom {
+            self.scroll_up(1);
+        } else if self.cursor.y + 1 < self.rows as usize {
+            self.cursor.shift_down(1);
+        }
+    }
+
+    /// Moves the cursor up a line, scrolling the region down if on the top margin
+    fn reverse_index(&mut self) {
+        let (top, _) = self.scroll_region();
+        if self.cursor.y == top {
+            self.scroll_down(1);
+        } else {
+            self.cursor.shift_up(1);
         }
     }
 
     /// Pushes a cell onto the current screen
     fn print(&mut self, char: char) {
-        let attr = self.renderer.attr.clone();
+        let char = self.state.translate(char);
+        if self.join_previous(char) {
+            return;
+        }
+
+        let cell = Cell::from_char(char, self.renderer.attr_id());
+        let (width, cols) = (cell.width(), self.cols as usize);
 
         // shells don't automatically do wrapping for applications
         // weird as hell
-        if self.cursor.x >= self.cols.into() {
-            self.cursor.shift_down(1);
-            self.cursor.set_x(0);
+        if self.cursor.wrap_pending || self.cursor.x + width > cols {
+            if self.state.dec_mode(DecPrivateModeCode::AutoWrap) {
+                self.current_line().wrapped = true;
+                self.new_line();
+                self.cursor.set_x(0);
+            } else {
+                // Without autowrap the end of the line keeps getting overwritten
+                self.cursor.set_x(cols.saturating_sub(width) as u32);
+            }
+        }
+
+        if self.state.insert_mode() {
+            let (x, empty) = (self.cursor.x, self.empty_cell());
+            self.current_line().insert_cells(x, width, empty, cols);
         }
 
         self.renderer.mut_screen(self.state.alt_screen).push(
-            Cell::new(char, attr),
+            cell,
             self.cursor.x,
             self.cursor.y,
         );
 
-        self.cursor.x += 1;
+        self.last_print = Some(self.cursor.get());
+        self.advance(width);
+        self.last_char = Some(char);
+    }
+
+    /// Moves the cursor past a printed cell
+    /// Stays on the last column, setting the pending wrap if autowrap is on
+    fn advance(&mut self, width: usize) {
+        let last = (self.cols as usize).saturating_sub(1);
+        let x = self.cursor.x + width;
+        if x > last {
+            self.cursor.set_x(last as u32);
+            self.cursor.wrap_pending = self.state.dec_mode(DecPrivateModeCode::AutoWrap);
+        } else {
+            self.cursor.set_x(x as u32);
+        }
+    }
+
+    /// Attaches a char to the previously printed cell if it continues its grapheme cluster
+    /// Returns false if the char needs a cell of its own
+    fn join_previous(&mut self, char: char) -> bool {
+        let Some((x, y)) = self.last_print else { return false };
+        let cursor_x = self.cursor.x + self.cursor.wrap_pending as usize;
+        let cols = self.cols as usize;
+        if y != self.cursor.y {
+            return false;
+        }
+
+        let line = self.current_line();
+        let Some(cell) = line.get_mut(x) else { return false };
+        if cursor_x != x + cell.width() || !cell.joins(char) {
+            return false;
+        }
+
+        let old_width = cell.width();
+        cell.push(char);
+
+        // Emoji presentation selectors can turn a narrow cell wide
+        if cell.width() > old_width {
+            if x + 1 >= cols {
+                cell.width = old_width as u8;
+                return true;
+            }
+            let spacer = Cell::spacer(cell.attr);
+            if line.len() <= x + 1 {
+                line.push(spacer);
+            } else {
+                line.split_wide(x + 1);
+                line[x + 1] = spacer;
+            }
+            self.advance(1);
+        }
+        true
     }
 
     // Prints each char of a string into the screen
@@ -251,7 +882,25 @@ impl Terminal {
     }
 
     fn handle_device(&mut self, device_command: Box<Device>) {
-        info!("Device Command {device_command:?}")
+        match *device_command {
+            // VT220 with sixel graphics and ANSI colour
+            Device::RequestPrimaryDeviceAttributes => self.respond("\x1b[?62;4;22c".into()),
+            Device::RequestSecondaryDeviceAttributes => self.respond("\x1b[>1;10;0c".into()),
+            Device::StatusReport => self.respond("\x1b[0n".into()),
+            Device::RequestTerminalNameAndVersion => {
+                self.respond(format!("\x1bP>|PreTTY({})\x1b\\", env!("CARGO_PKG_VERSION")))
+            }
+            _ => info!("Device Command {device_command:?}"),
+        }
+    }
+
+    /// CPR, reports the one-based cursor position
+    /// In origin mode the line is relative to the top margin
+    fn report_cursor_position(&mut self) {
+        let x = self.cursor.x.min((self.cols as usize).saturating_sub(1));
+        let top = if self.origin_mode() { self.scroll_region().0 } else { 0 };
+        let y = self.cursor.y.saturating_sub(top);
+        self.respond(format!("\x1b[{};{}R", y + 1, x + 1));
     }
 
     // Operating System Commands
@@ -265,11 +914,79 @@ impl Terminal {
             FinalTermSemanticPrompt(ftsprompt) => self.handle_fts_prompt(ftsprompt),
             ITermProprietary(iterm_command) => self.handle_iterm(iterm_command),
             SystemNotification(notif) => self.window.send_notification(notif),
-            CurrentWorkingDirectory(cwd) => self.state.cwd = cwd,
+            CurrentWorkingDirectory(url) => match WorkingDirectory::parse(&url) {
+                Some(cwd) => self.state.cwd = Some(cwd),
+                None => warn!("Working directory is not a file url: {url}"),
+            },
+            SetHyperlink(link) => self.renderer.attr_mut().set_hyperlink(link),
+            ChangeColorNumber(pairs) => self.change_colours(pairs),
+            ChangeDynamicColors(first, colours) => self.change_dynamic_colours(first, colours),
+            ResetDynamicColor(which) => self.colours.set_dynamic(which, None),
+            ResetColors(indices) => self.colours.reset_palette(&indices),
+            SetSelection(selection, text) => self.set_clipboard(selection, text),
+            ClearSelection(selection) => self.set_clipboard(selection, String::new()),
+            QuerySelection(selection) => {
+                if let Some(clipboard) = Clipboard::from_selection(selection) {
+                    self.window.request_clipboard(clipboard);
+                }
+            }
             _ => info!("OperatingSystemCommand({:?})", command),
         };
     }
 
+    /// OSC 52, cut buffers aren't supported
+    fn set_clipboard(&mut self, selection: Selection, text: String) {
+        match Clipboard::from_selection(selection) {
+            Some(clipboard) => self.window.set_clipboard(clipboard, text),
+            None => info!("Ignoring OSC 52 for {selection}"),
+        }
+    }
+
+    /// Answers an OSC 52 query once the window has read the clipboard
+    pub fn clipboard_reply(&mut self, clipboard: Clipboard, text: &str) {
+        let name = match clipboard {
+            Clipboard::Clipboard => 'c',
+            Clipboard::Primary => 'p',
+        };
+        let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+        self.respond(format!("\x1b]52;{name};{encoded}\x1b\\"));
+    }
+
+    /// OSC 4, sets or queries palette entries
+    fn change_colours(&mut self, pairs: Vec<ChangeColorPair>) {
+        for pair in pairs {
+            let index = pair.palette_index;
+            match pair.color {
+                ColorOrQuery::Color(colour) => {
+                    self.colours.palette.insert(index, to_rgb(colour));
+                }
+                ColorOrQuery::Query => {
+                    let colour = x11_string(self.colours.palette(index));
+                    self.respond(format!("\x1b]4;{index};{colour}\x1b\\"));
+                }
+            }
+        }
+    }
+
+    /// OSC 10, 11 and 12, each extra colour moves on to the next dynamic colour like xterm
+    fn change_dynamic_colours(&mut self, first: DynamicColorNumber, colours: Vec<ColorOrQuery>) {
+        for (offset, colour) in colours.into_iter().enumerate() {
+            let number = first as u8 + offset as u8;
+            let Some(which) = DynamicColorNumber::from_u8(number) else { break };
+            match colour {
+                ColorOrQuery::Color(colour) => {
+                    self.colours.set_dynamic(which, Some(to_rgb(colour)));
+                }
+                ColorOrQuery::Query => {
+                    if let Some(current) = self.colours.dynamic(which) {
+                        let current = x11_string(current);
+                        self.respond(format!("\x1b]{number};{current}\x1b\\"));
+                    }
+                }
+            }
+        }
+    }
+
     /// Handling of all Iterm-based commands
     fn handle_iterm(&mut self, command: ITermProprietary) {
         use ITermProprietary::*;
@@ -277,14 +994,88 @@ impl Terminal {
             SetUserVar { name, value } => {
                 self.user_vars.insert(name, value);
             }
-            ClearScrollback => self.mut_screen().clear_scrollback(),
+            ClearScrollback => self.clear_scrollback(),
             StealFocus => self.window.steal_focus(),
             SetMark => self.set_mark(),
             SetProfile(profile) => self.set_profile(profile),
+            File(file) => self.iterm_file(*file),
             _ => info!("ITERM2 {command:?}"),
         }
     }
 
+    /// Shows an inline image from imgcat and the like, leaving the cursor after it
+    /// Files that aren't inline are meant to be downloaded, which isn't supported
+    fn iterm_file(&mut self, file: ITermFileData) {
+        if !file.inline {
+            info!("ITERM2 Download {:?}", file.name);
+            return;
+        }
+        let image = match self::image::decode_file(&file.data) {
+            Ok(image) => image,
+            Err(err) => {
+                warn!("Failed to decode iTerm2 image {:?}: {err}", file.name);
+                return;
+            }
+        };
+
+        let (width, height) = self.iterm_size(&file, image.width, image.height);
+        let (cols, rows) = self.image_cells(width, height);
+        let mut placement = ImagePlacement::new(Arc::new(image), cols, rows);
+        placement.width = width;
+        placement.height = height;
+
+        if file.do_not_move_cursor {
+            placement.x = self.cursor.x;
+            placement.line = self.phys_cursor_y();
+            self.mut_screen().place_image(placement);
+        } else {
+            self.place_image(placement);
+            self.advance(cols);
+        }
+    }
+
+    /// Pixel size an iTerm2 image is drawn at
+    /// An automatic side follows the aspect ratio if it is kept, otherwise it is the image's own,
+    /// and images with no size at all shrink to fit the width of the screen
+    /// Whatever size is asked for, it is clamped to a few screens
+    fn iterm_size(&self, file: &ITermFileData, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = self.iterm_unclamped_size(file, width, height);
+        self.clamp_image_size(width, height)
+    }
+
+    fn iterm_unclamped_size(&self, file: &ITermFileData, width: u32, height: u32) -> (u32, u32) {
+        let (cell_width, cell_height) = self.cell_size;
+        let screen = (
+            (self.cols as u32).saturating_mul(cell_width),
+            (self.rows as u32).saturating_mul(cell_height),
+        );
+        let to_u32 = |n: i64| u32::try_from(n.max(0)).unwrap_or(u32::MAX);
+        let resolve = |dimension: ITermDimension, cell: u32, screen: u32| match dimension {
+            ITermDimension::Automatic => None,
+            ITermDimension::Cells(cells) => Some(to_u32(cells).saturating_mul(cell)),
+            ITermDimension::Pixels(pixels) => Some(to_u32(pixels)),
+            ITermDimension::Percent(percent) => Some(scale(screen, 100, to_u32(percent.min(100)))),
+        };
+        let target_width = resolve(file.width, cell_width, screen.0);
+        let target_height = resolve(file.height, cell_height, screen.1);
+        let keep = file.preserve_aspect_ratio;
+
+        match (target_width, target_height) {
+            (None, None) if width > screen.0 => (screen.0, scale(height, width, screen.0)),
+            (None, None) => (width, height),
+            (Some(w), None) if keep => (w, scale(height, width, w)),
+            (None, Some(h)) if keep => (scale(width, height, h), h),
+            (Some(w), None) => (w, height),
+            (None, Some(h)) => (width, h),
+            // Fits inside the box without stretching
+            (Some(w), Some(h)) if keep => match scale(height, width, w) {
+                fitted if fitted <= h => (w, fitted),
+                _ => (scale(width, height, h), h),
+            },
+            (Some(w), Some(h)) => (w, h),
+        }
+    }
+
     /// Basically vim marks system, aka bookmark for cursor positions
     fn set_mark(&mut self) { self.marks.push(self.cursor_pos()); }
 
@@ -296,116 +1087,548 @@ impl Terminal {
 
 // Prompt Management
 impl Terminal {
-    // TODO: Replace this with a more explicit system
-    // Ideally there would be a Command object, that has prompt, input and output fields
     fn handle_fts_prompt(&mut self, prompt: FinalTermSemanticPrompt) {
         use FinalTermSemanticPrompt::*;
 
         match prompt {
             FreshLine => self.fresh_line(),
-            FreshLineAndStartPrompt { .. } | MarkEndOfCommandWithFreshLine { .. } => {
+            FreshLineAndStartPrompt { aid, .. }
+            | MarkEndOfCommandWithFreshLine { aid, .. } => {
                 self.fresh_line();
-                self.start_command();
+                self.start_command(aid);
             }
             StartPrompt(prompt_kind) => self
                 .renderer
-                .attr
+                .attr_mut()
                 .set_sem_type(SemanticType::Prompt(PromptKind::from(prompt_kind))),
             // why are these so long :sob:
             MarkEndOfPromptAndStartOfInputUntilNextMarker => {
                 self.start_input(Until::SemanticMarker)
             }
             MarkEndOfPromptAndStartOfInputUntilEndOfLine => self.start_input(Until::LineEnd),
-            MarkEndOfInputAndStartOfOutput { aid: _ } => {
-                self.renderer.attr.set_sem_type(SemanticType::Output);
-                self.commands
-                    .start_output(self.cursor.x, self.screen().phys_line(self.cursor.y));
+            MarkEndOfInputAndStartOfOutput { aid } => self.start_output(aid),
+            CommandStatus { status, aid } => {
+                self.commands.set_aid(aid);
+                self.commands.set_status(status);
             }
-            CommandStatus { status, aid: _ } => self.commands.set_status(status),
         }
     }
 
     /// Creates a 'Fresh Line' as described by the FinalTermSemanticPrompt protocol
     fn fresh_line(&mut self) {
-        if self.cursor.x == 0 {
+        if self.cursor.x == 0 && !self.cursor.wrap_pending {
             return;
         }
         self.new_line();
-        self.cursor.x = 0;
+        self.cursor.set_x(0);
     }
 
-    fn start_command(&mut self) {
+    fn start_command(&mut self, aid: Option<String>) {
         self.renderer
-            .attr
+            .attr_mut()
             .set_sem_type(SemanticType::Prompt(PromptKind::Initial));
         self.commands
-            .start_new(self.cursor.x, self.screen().phys_line(self.cursor.y));
+            .start_new(self.cursor.x, self.screen().phys_line(self.cursor.y), aid);
     }
 
     fn start_input(&mut self, until: Until) {
-        self.renderer.attr.set_sem_type(SemanticType::Input(until));
+        self.renderer.attr_mut().set_sem_type(SemanticType::Input(until));
         self.commands
             .start_input(self.cursor.x, self.screen().phys_line(self.cursor.y));
         // TODO: Do some state management. maybe some sort of custom editor?
     }
+
+    /// The command line is finished once output starts, so its text is read off the screen
+    fn start_output(&mut self, aid: Option<String>) {
+        self.renderer.attr_mut().set_sem_type(SemanticType::Output);
+        let y = self.screen().phys_line(self.cursor.y);
+        self.commands.start_output(self.cursor.x, y, self.state.cwd.clone());
+        self.commands.set_aid(aid);
+
+        let text = self.command_text(self.commands.len() - 1, CommandZone::Input);
+        self.commands.set_text(text.unwrap_or_default());
+    }
+
+    /// Plain text of part of a command, None if the command or zone doesn't exist
+    /// Commands are only tracked on the primary screen
+    pub fn command_text(&self, index: usize, zone: CommandZone) -> Option<String> {
+        let (start, end) = self.commands.command(index)?.zone(zone)?;
+        let end = end.map(|end| (end.x, end.y));
+        Some(self.renderer.screen.text_between((start.x, start.y), end))
+    }
 }
 
-// Erase functions
+// Kitty graphics functions
 impl Terminal {
-    fn empty_cell(&self) -> Cell {
-        Cell { text: ' ', attr: self.renderer.attr.clone() }
+    fn kitty_image(&mut self, image: Box<KittyImage>) {
+        match *image {
+            KittyImage::TransmitData { transmit, verbosity } => {
+                self.kitty_transfer(Transfer { transmit, placement: None, verbosity, query: false })
+            }
+            KittyImage::TransmitDataAndDisplay { transmit, placement, verbosity } => {
+                let placement = Some(placement);
+                self.kitty_transfer(Transfer { transmit, placement, verbosity, query: false })
+            }
+            KittyImage::Query { transmit } => {
+                let verbosity = KittyImageVerbosity::Verbose;
+                self.kitty_transfer(Transfer { transmit, placement: None, verbosity, query: true })
+            }
+            KittyImage::Display { image_id, image_number, placement, verbosity } => {
+                let id = image_id.or_else(|| self.images.find_number(image_number?));
+                let result = match id {
+                    Some(id) => self.kitty_display(id, &placement),
+                    None => Err("ENOENT:no such image".into()),
+                };
+                let placement_id = placement.placement_id;
+                self.kitty_respond(image_id, image_number, placement_id, result, verbosity);
+            }
+            KittyImage::Delete { what, .. } => self.kitty_delete(what),
+            KittyImage::TransmitFrame { transmit, verbosity, .. } => {
+                let result = Err("EINVAL:animation is not supported".into());
+                let (id, number) = (transmit.image_id, transmit.image_number);
+                self.kitty_respond(id, number, None, result, verbosity);
+            }
+            KittyImage::ComposeFrame { frame, .. } => info!("Kitty Compose Frame {frame:?}"),
+        }
     }
 
-    fn erase_in_display(&mut self, edit: EraseInDisplay) {
-        let empty = self.empty_cell();
-        let screen = self.renderer.mut_screen(self.state.alt_screen);
+    /// Loads an image once all of its chunks have arrived, then stores and places it
+    fn kitty_transfer(&mut self, transfer: Transfer) {
+        let transfer = match self.images.receive(transfer) {
+            Ok(Some(transfer)) => transfer,
+            Ok(None) => return,
+            Err((dropped, err)) => {
+                let (id, number) = (dropped.transmit.image_id, dropped.transmit.image_number);
+                let placement_id = dropped.placement.and_then(|placement| placement.placement_id);
+                self.kitty_respond(id, number, placement_id, Err(err), dropped.verbosity);
+                return;
+            }
+        };
+        let Transfer { transmit, placement, verbosity, query } = transfer;
+        let (mut id, number) = (transmit.image_id, transmit.image_number);
+        let placement_id = placement.as_ref().and_then(|placement| placement.placement_id);
 
-        match edit {
-            EraseInDisplay::EraseDisplay => {
-                //screen.ensure_lines(self.rows as usize);
-                for i in 0..screen.len() {
-                    let cols = self.cols as usize; 
-                    let line = screen.mut_line(i);
-                    *line = Line::repeat(empty.clone(), cols);
+        let result = match kitty::load(&transmit) {
+            Ok(_) if query => Ok(()),
+            Ok(image) => {
+                let stored = self.images.insert(image, id, number);
+                // Images sent with just a number are told the id they were given
+                if number.is_some() {
+                    id = Some(stored);
+                }
+                match placement {
+                    Some(placement) => self.kitty_display(stored, &placement),
+                    None => Ok(()),
                 }
             }
-            _ => info!("Erase {edit:?}"),
+            Err(err) => Err(err),
+        };
+        self.kitty_respond(id, number, placement_id, result, verbosity);
+    }
+
+    /// Replies to a command as far as its verbosity allows
+    /// Commands without an id or number never get a reply
+    fn kitty_respond(
+        &mut self,
+        id: Option<u32>,
+        number: Option<u32>,
+        placement_id: Option<u32>,
+        result: Result<(), String>,
+        verbosity: KittyImageVerbosity,
+    ) {
+        if id.is_none() && number.is_none() {
+            return;
         }
+        let message = match (&result, verbosity) {
+            (_, KittyImageVerbosity::Quiet) | (Ok(_), KittyImageVerbosity::OnlyErrors) => return,
+            (Ok(_), _) => "OK",
+            (Err(err), _) => err.as_str(),
+        };
+
+        let keys = [("i", id), ("I", number), ("p", placement_id)]
+            .into_iter()
+            .filter_map(|(key, value)| Some(format!("{key}={}", value?)))
+            .collect::<Vec<_>>();
+        self.respond(format!("\x1b_G{};{message}\x1b\\", keys.join(",")));
     }
 
-    fn erase_in_line(&mut self, edit: EraseInLine) {
-        let screen = self.renderer.mut_screen(self.state.alt_screen);
-        let start = self.cursor.x;
-        let y = self.cursor.y;
-        let empty = self.empty_cell();
+    /// Places a stored image at the cursor
+    /// Columns and rows stretch the image over them, otherwise it is drawn at its own size
+    fn kitty_display(&mut self, id: u32, placement: &KittyImagePlacement) -> Result<(), String> {
+        let stored = self.images.get(id).ok_or("ENOENT:no such image")?;
+        let mut image = stored.image.clone();
 
-        match edit {
-            EraseInLine::EraseToEndOfLine => {
-                let line = self.mut_screen().mut_line(y);
+        // A source rectangle shows only part of the image
+        let source = [placement.x, placement.y, placement.w, placement.h];
+        if source.iter().any(Option::is_some) {
+            let (x, y) = (placement.x.unwrap_or(0), placement.y.unwrap_or(0));
+            let width = placement.w.unwrap_or(image.width);
+            let height = placement.h.unwrap_or(image.height);
+            image = Arc::new(image.crop(x, y, width, height));
+        }
 
-                for x in start..line.len() {
-                    line[x] = empty.clone();
+        let (cell_width, cell_height) = self.cell_size;
+        let (width, height) = match (placement.columns, placement.rows) {
+            (Some(cols), Some(rows)) => {
+                (cols.saturating_mul(cell_width), rows.saturating_mul(cell_height))
+            }
+            (Some(cols), None) => {
+                let width = cols.saturating_mul(cell_width);
+                (width, scale(image.height, image.width, width))
+            }
+            (None, Some(rows)) => {
+                let height = rows.saturating_mul(cell_height);
+                (scale(image.width, image.height, height), height)
+            }
+            (None, None) => (image.width, image.height),
+        };
+        let (width, height) = self.clamp_image_size(width, height);
+        let offset = (
+            placement.x_offset.unwrap_or(0).min(cell_width - 1),
+            placement.y_offset.unwrap_or(0).min(cell_height - 1),
+        );
+        let (cols, rows) =
+            self.image_cells(width.saturating_add(offset.0), height.saturating_add(offset.1));
+        if let Some(stored) = self.images.get_mut(id) {
+            stored.cells = Some((cols, rows));
+        }
+
+        let mut new = ImagePlacement::new(image, cols, rows);
+        new.width = width;
+        new.height = height;
+        new.offset = offset;
+        new.image_id = id;
+        new.placement_id = placement.placement_id.unwrap_or(0);
+        new.z_index = placement.z_index.unwrap_or(0);
+
+        // Placing with the same placement id again moves it
+        let placement_id = new.placement_id;
+        if placement_id != 0 {
+            let moved = |p: &ImagePlacement| p.image_id == id && p.placement_id == placement_id;
+            self.mut_screen().remove_images(moved);
+        }
+
+        if placement.do_not_move_cursor {
+            new.x = self.cursor.x;
+            new.line = self.phys_cursor_y();
+            self.mut_screen().place_image(new);
+        } else {
+            // The cursor ends up just past the bottom right of the image
+            self.place_image(new);
+            self.advance(cols);
+        }
+        Ok(())
+    }
+
+    /// Deletes kitty placements, leaving images from other protocols alone
+    /// Uppercase deletes also free images that no longer have any placements
+    fn kitty_delete(&mut self, what: KittyImageDelete) {
+        use KittyImageDelete::*;
+        type Filter = Box<dyn Fn(&ImagePlacement) -> bool>;
+
+        let top = self.screen().phys_line(0);
+        let visible = top..top + self.rows as usize;
+        let (cursor_x, cursor_y) = (self.cursor.x, self.phys_cursor_y());
+        // Cells in delete commands are one-based and relative to the visible screen
+        let cell = |x: u32, y: u32| {
+            ((x as usize).saturating_sub(1), top + (y as usize).saturating_sub(1))
+        };
+        let block = |lines: Range<usize>, columns: Range<usize>| -> Filter {
+            Box::new(move |p: &ImagePlacement| p.overlaps(&lines, &columns))
+        };
+        let by_id = |id: u32, placement_id: Option<u32>| -> Filter {
+            Box::new(move |p: &ImagePlacement| {
+                p.image_id == id && placement_id.map_or(true, |pid| p.placement_id == pid)
+            })
+        };
+
+        let (remove, delete): (Filter, bool) = match what {
+            All { delete } => (block(visible, 0..usize::MAX), delete),
+            ByImageId { image_id, placement_id, delete } => (by_id(image_id, placement_id), delete),
+            ByImageNumber { image_number, placement_id, delete } => {
+                let Some(image_id) = self.images.find_number(image_number) else { return };
+                (by_id(image_id, placement_id), delete)
+            }
+            AtCursorPosition { delete } => {
+                (block(cursor_y..cursor_y + 1, cursor_x..cursor_x + 1), delete)
+            }
+            AnimationFrames { .. } => return,
+            DeleteAt { x, y, delete } => {
+                let (x, y) = cell(x, y);
+                (block(y..y + 1, x..x + 1), delete)
+            }
+            DeleteAtZ { x, y, z, delete } => {
+                let (x, y) = cell(x, y);
+                let at = block(y..y + 1, x..x + 1);
+                (Box::new(move |p: &ImagePlacement| p.z_index == z && at(p)), delete)
+            }
+            DeleteColumn { x, delete } => {
+                let (x, _) = cell(x, 1);
+                (block(0..usize::MAX, x..x + 1), delete)
+            }
+            DeleteRow { y, delete } => {
+                let (_, y) = cell(1, y);
+                (block(y..y + 1, 0..usize::MAX), delete)
+            }
+            DeleteZ { z, delete } => (Box::new(move |p: &ImagePlacement| p.z_index == z), delete),
+        };
+
+        let removed = self.mut_screen().remove_images(|p| p.image_id != 0 && remove(p));
+        if !delete {
+            return;
+        }
+        for placement in removed {
+            let id = placement.image_id;
+            let screens = [&self.renderer.screen, &self.renderer.alt_screen];
+            if !screens.iter().any(|screen| screen.images().iter().any(|p| p.image_id == id)) {
+                self.images.remove(id);
+            }
+        }
+    }
+
+    /// Runs of placeholder cells on a line of the current screen
+    /// The image id is in the foreground colour, and diacritics give the row and column,
+    /// which carry on from the cell to the left when they are left out
+    pub fn placeholders(&self, index: usize) -> Vec<PlaceholderRun> {
+        let mut runs: Vec<PlaceholderRun> = Vec::new();
+        let Some(line) = self.screen().line(index) else { return runs };
+        let mut previous: Option<(u32, usize, usize)> = None;
+
+        for (x, cell) in line.iter().enumerate() {
+            let id = match cell.is_placeholder() {
+                true => kitty::placeholder_id(self.cell_attr(cell).get_fg()),
+                false => None,
+            };
+            let Some(mut id) = id else {
+                previous = None;
+                continue;
+            };
+
+            let mut marks = cell.text.chars().skip(1).map(kitty::diacritic_index);
+            let (row, col) = (marks.next().flatten(), marks.next().flatten());
+            if let Some(high) = marks.next().flatten() {
+                id |= (high as u32) << 24;
+            }
+            let (row, col) = match (row, col, previous) {
+                (Some(row), Some(col), _) => (row, col),
+                (None, None, Some((last, row, col))) if last == id => (row, col + 1),
+                (Some(row), None, Some((last, last_row, col))) if last == id && last_row == row => {
+                    (row, col + 1)
+                }
+                (row, _, _) => (row.unwrap_or(0), 0),
+            };
+            previous = Some((id, row, col));
+
+            let Some(stored) = self.images.get(id) else { continue };
+            match runs.last_mut() {
+                Some(run)
+                    if run.image_id == id
+                        && run.row == row
+                        && run.x + run.width == x
+                        && run.col + run.width == col =>
+                {
+                    run.width += 1
+                }
+                _ => {
+                    let (width, height) = (stored.image.width, stored.image.height);
+                    let (cols, rows) = stored.cells.unwrap_or(self.image_cells(width, height));
+                    let image = stored.image.clone();
+                    let image_id = id;
+                    runs.push(PlaceholderRun { image, image_id, x, width: 1, row, col, cols, rows });
                 }
             }
-            EraseInLine::EraseToStartOfLine => {
+        }
+        runs
+    }
+}
+
+// Implicit hyperlinks
+impl Terminal {
+    /// Sets the rules implicit links are found with, and the schemes they are allowed to have
+    pub fn set_link_rules(&mut self, rules: Vec<Rule>, schemes: Vec<String>) {
+        self.link_rules = rules;
+        self.link_schemes = schemes;
+    }
+
+    /// Links text in the logical line that just ended on the cursor line, using the link rules
+    /// Only the primary screen is checked, as full screen applications redraw freely
+    /// Text that already has a link, explicit or from a longer match, keeps it
+    fn detect_links(&mut self) {
+        if self.link_rules.is_empty() || self.state.alt_screen {
+            return;
+        }
+        let screen = &self.renderer.screen;
+        let end = self.phys_cursor_y();
+        let mut start = end;
+        // Very long lines only have their end checked, so each line feed does bounded work
+        while start > 0
+            && end - start < MAX_LINK_ROWS
+            && screen.line(start - 1).is_some_and(|line| line.wrapped)
+        {
+            start -= 1;
+        }
+
+        // The line's text, and for each cell where its text starts and if it has a link
+        let mut text = String::new();
+        let mut cells = Vec::new();
+        for index in start..=end {
+            let Some(line) = screen.line(index) else { break };
+            for (x, cell) in line.iter().enumerate().filter(|(_, cell)| !cell.is_spacer()) {
+                let linked = self.renderer.attrs.get(cell.attr).hyperlink().is_some();
+                cells.push((text.len(), index, x, linked));
+                text.push_str(&cell.text);
+            }
+        }
+
+        let mut links = Vec::new();
+        for found in Rule::match_hyperlinks(&text, &self.link_rules) {
+            if cells.iter().any(|cell| found.range.contains(&cell.0) && cell.3) {
+                continue;
+            }
+            // Links that wouldn't open aren't shown as links either
+            let uri = resolve_link(found.link.uri(), self.state.cwd.as_ref());
+            if !link_allowed(&uri, &self.link_schemes) {
+                continue;
+            }
+            for cell in cells.iter_mut().filter(|cell| found.range.contains(&cell.0)) {
+                cell.3 = true;
+            }
+            links.push((found.range, Hyperlink::new_implicit(uri)));
+        }
+
+        for (range, link) in links {
+            for &(_, index, x, _) in cells.iter().filter(|cell| range.contains(&cell.0)) {
+                let Some(line) = self.renderer.screen.line_mut(index) else { continue };
+                let mut attr = self.renderer.attrs.get(line[x].attr).clone();
+                attr.set_hyperlink(Some(link.clone()));
+                line[x].attr = self.renderer.attrs.intern(&attr);
+            }
+        }
+    }
+}
+
+// Erase functions
+impl Terminal {
+    fn empty_cell(&mut self) -> Cell {
+        let background = self.renderer.attr().background();
+        Cell::blank(self.renderer.attrs.intern(&background))
+    }
+
+    fn erase_in_display(&mut self, edit: EraseInDisplay) {
+        let (rows, cols) = (self.rows as usize, self.cols as usize);
+        let empty = self.empty_cell();
+        let y = self.cursor.y;
+
+        // The cursor line is partially erased, then whole lines
+        let lines = match edit {
+            EraseInDisplay::EraseToEndOfDisplay => {
+                self.erase_in_line(EraseInLine::EraseToEndOfLine);
+                y + 1..rows
+            }
+            EraseInDisplay::EraseToStartOfDisplay => {
+                self.erase_in_line(EraseInLine::EraseToStartOfLine);
+                0..y
+            }
+            EraseInDisplay::EraseDisplay => 0..rows,
+            EraseInDisplay::EraseScrollback => {
+                self.clear_scrollback();
+                return;
+            }
+        };
+
+        let screen = self.mut_screen();
+        screen.erase_images(lines.clone(), 0..cols);
+        for i in lines {
+            *screen.mut_line(i) = Line::repeat(empty.clone(), cols);
+        }
+    }
+
+    fn erase_in_line(&mut self, edit: EraseInLine) {
+        let cols = self.cols as usize;
+        let start = self.cursor.x;
+        let y = self.cursor.y;
+        let empty = self.empty_cell();
+
+        let columns = match edit {
+            EraseInLine::EraseToEndOfLine => start..cols,
+            EraseInLine::EraseToStartOfLine => 0..start + 1,
+            EraseInLine::EraseLine => 0..cols,
+        };
+        self.mut_screen().erase_images(y..y + 1, columns);
+
+        match edit {
+            EraseInLine::EraseToEndOfLine => {
                 let line = self.mut_screen().mut_line(y);
 
                 for x in start..line.len() {
                     line[x] = empty.clone();
                 }
             }
+            EraseInLine::EraseToStartOfLine => {
+                let line = self.mut_screen().mut_line(y);
+
+                for x in 0..=start.min(line.len().saturating_sub(1)) {
+                    line[x] = empty.clone();
+                }
+            }
             EraseInLine::EraseLine => {
-                *self.mut_screen().mut_line(y) = Line::default();
+                *self.mut_screen().mut_line(y) = Line::repeat(empty, cols);
             }
         }
     }
 
     fn erase_characters(&mut self, n: u32) {
+        let empty = self.empty_cell();
         let screen = self.renderer.mut_screen(self.state.alt_screen);
         let end = (self.cursor.x + n as usize).min(screen.mut_line(self.cursor.y).len());
 
         for x in self.cursor.x..end {
-            screen.mut_line(self.cursor.y)[x] = Cell::default();
+            screen.mut_line(self.cursor.y)[x] = empty.clone();
+        }
+    }
+
+    fn insert_characters(&mut self, n: u32) {
+        let (x, cols, empty) = (self.cursor.x, self.cols as usize, self.empty_cell());
+        self.current_line().insert_cells(x, n as usize, empty, cols);
+    }
+
+    fn delete_characters(&mut self, n: u32) {
+        let (x, cols, empty) = (self.cursor.x, self.cols as usize, self.empty_cell());
+        self.current_line().delete_cells(x, n as usize, empty, cols);
+    }
+
+    /// Inserts lines at the cursor, only within the scroll region
+    fn insert_lines(&mut self, n: u32) {
+        let (top, bottom) = self.scroll_region();
+        let y = self.cursor.y;
+        if y < top || y > bottom {
+            return;
+        }
+
+        let empty = self.empty_cell();
+        self.mut_screen().insert_lines(y, bottom, n as usize, empty);
+        self.cursor.set_x(0);
+    }
+
+    /// Deletes lines at the cursor, only within the scroll region
+    fn delete_lines(&mut self, n: u32) {
+        let (top, bottom) = self.scroll_region();
+        let y = self.cursor.y;
+        if y < top || y > bottom {
+            return;
+        }
+
+        let empty = self.empty_cell();
+        self.mut_screen().delete_lines(y, bottom, n as usize, empty);
+        self.cursor.set_x(0);
+    }
+
+    /// Repeats the last printed character
+    fn repeat_char(&mut self, n: u32) {
+        if let Some(char) = self.last_char {
+            for _ in 0..n {
+                self.print(char);
+            }
         }
     }
 
@@ -414,13 +1637,22 @@ impl Terminal {
             Edit::EraseInLine(e) => self.erase_in_line(e),
             Edit::EraseInDisplay(e) => self.erase_in_display(e),
             Edit::EraseCharacter(n) => self.erase_characters(n),
-            _ => info!("EDIT {edit:?}"),
+            Edit::ScrollUp(n) => self.scroll_up(n as usize),
+            Edit::ScrollDown(n) => self.scroll_down(n as usize),
+            Edit::InsertCharacter(n) => self.insert_characters(n),
+            Edit::DeleteCharacter(n) => self.delete_characters(n),
+            Edit::InsertLine(n) => self.insert_lines(n),
+            Edit::DeleteLine(n) => self.delete_lines(n),
+            Edit::Repeat(n) => self.repeat_char(n),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
     use termwiz::escape::csi::DecPrivateModeCode::EnableAlternateScreen;
     use termwiz::escape::csi::{DecPrivateMode, Mode};
 
@@ -448,6 +1680,819 @@ mod tests {
 
     #[test]
     pub fn clear_line() {
-        // No clue how I'm gonna do this
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"abcdef\x1b[1;3H\x1b[1K");
+        assert_eq!(line_text(&terminal, 0), "   def");
+
+        terminal.feed(b"\x1b[0K");
+        assert_eq!(line_text(&terminal, 0), "");
+    }
+
+    #[test]
+    pub fn feed_split_sequence() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[3");
+        terminal.feed(b";5Hx");
+        assert_eq!(terminal.cursor.get(), (5, 2));
+        assert_eq!(line_text(&terminal, 2), "    x");
+    }
+
+    fn line_text(terminal: &Terminal, y: usize) -> String {
+        let index = terminal.screen().phys_line(y);
+        match terminal.screen().line(index) {
+            Some(line) => line.iter().map(|c| c.text.as_str()).collect::<String>().trim_end().to_string(),
+            None => String::new(),
+        }
+    }
+
+    #[test]
+    pub fn scroll_region_line_feed() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 10);
+
+        terminal.feed(b"a\r\nb\r\nc\r\nd\r\nstatus");
+        terminal.feed(b"\x1b[1;4r\x1b[4;1H\n");
+
+        assert_eq!(line_text(&terminal, 0), "b");
+        assert_eq!(line_text(&terminal, 2), "d");
+        assert_eq!(line_text(&terminal, 3), "");
+        assert_eq!(line_text(&terminal, 4), "status");
+    }
+
+    #[test]
+    pub fn scroll_region_without_rows() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(0, 10);
+
+        // A window shorter than a cell has no rows, resetting the region is ignored
+        terminal.feed(b"\x1b[r");
+        assert_eq!(terminal.state.scroll_region, None);
+        assert_eq!(terminal.scroll_region(), (0, 0));
+    }
+
+    #[test]
+    pub fn scroll_up_and_down() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(4, 10);
+
+        terminal.feed(b"1\r\n2\r\n3\r\n4");
+        terminal.feed(b"\x1b[2;3r\x1b[S");
+        assert_eq!(line_text(&terminal, 1), "3");
+        assert_eq!(line_text(&terminal, 2), "");
+
+        terminal.feed(b"\x1b[2T");
+        assert_eq!(line_text(&terminal, 0), "1");
+        assert_eq!(line_text(&terminal, 1), "");
+        assert_eq!(line_text(&terminal, 2), "");
+        assert_eq!(line_text(&terminal, 3), "4");
+    }
+
+    #[test]
+    pub fn alt_screen_no_scrollback() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 10);
+
+        terminal.feed(b"\x1b[?47h");
+        terminal.feed(b"a\r\nb\r\nc\r\nd\r\ne");
+
+        assert_eq!(terminal.screen().visible_start(), 0);
+        assert_eq!(terminal.screen().len(), 3);
+        assert_eq!(line_text(&terminal, 0), "c");
+    }
+
+    #[test]
+    pub fn insert_delete_characters() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 8);
+
+        terminal.feed(b"abcdefgh\x1b[1;3H\x1b[2@");
+        assert_eq!(line_text(&terminal, 0), "ab  cdef");
+
+        terminal.feed(b"\x1b[3P");
+        assert_eq!(line_text(&terminal, 0), "abdef");
+    }
+
+    #[test]
+    pub fn erased_cells_keep_background() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 8);
+
+        terminal.feed(b"abc\x1b[1;1H\x1b[1;41m\x1b[P");
+        let cell = terminal.screen().cell(7, 0);
+        let attr = terminal.cell_attr(&cell);
+        assert_eq!(attr.get_bg(), termwiz::color::ColorSpec::PaletteIndex(1));
+        assert!(!attr.bold());
+    }
+
+    #[test]
+    pub fn insert_delete_lines_in_region() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(4, 8);
+
+        terminal.feed(b"1\r\n2\r\n3\r\n4\x1b[1;3r\x1b[2;1H\x1b[L");
+        assert_eq!(line_text(&terminal, 1), "");
+        assert_eq!(line_text(&terminal, 2), "2");
+        assert_eq!(line_text(&terminal, 3), "4");
+
+        terminal.feed(b"\x1b[2M");
+        assert_eq!(line_text(&terminal, 0), "1");
+        assert_eq!(line_text(&terminal, 1), "");
+        assert_eq!(line_text(&terminal, 3), "4");
+    }
+
+    #[test]
+    pub fn reflow_wrapped_lines() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 8);
+
+        terminal.feed(b"abcdefghij");
+        assert!(terminal.screen().line(0).unwrap().wrapped);
+
+        terminal.resize(5, 5);
+        assert_eq!(line_text(&terminal, 0), "abcde");
+        assert_eq!(line_text(&terminal, 1), "fghij");
+        assert_eq!(terminal.cursor.get(), (4, 1));
+        assert!(terminal.cursor.wrap_pending);
+
+        terminal.resize(5, 12);
+        assert_eq!(line_text(&terminal, 0), "abcdefghij");
+        assert_eq!(line_text(&terminal, 1), "");
+        assert_eq!(terminal.cursor.get(), (10, 0));
+    }
+
+    #[test]
+    pub fn reflow_keeps_hard_breaks() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 10);
+
+        terminal.feed(b"abcdef\r\nxy");
+        terminal.resize(5, 4);
+        assert_eq!(line_text(&terminal, 0), "abcd");
+        assert_eq!(line_text(&terminal, 1), "ef");
+        assert_eq!(line_text(&terminal, 2), "xy");
+        assert_eq!(terminal.cursor.get(), (2, 2));
+    }
+
+    #[test]
+    pub fn wide_characters() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 5);
+
+        terminal.feed("a漢字".as_bytes());
+        assert_eq!(terminal.cursor.get(), (4, 0));
+        assert!(terminal.cursor.wrap_pending);
+        assert!(terminal.screen().cell(2, 0).is_spacer());
+
+        // Doesn't fit in the last column so it wraps
+        terminal.feed("\x1b[1;5H語".as_bytes());
+        assert_eq!(terminal.cursor.get(), (2, 1));
+        assert_eq!(terminal.screen().cell(0, 1).text, "語");
+    }
+
+    #[test]
+    pub fn overwrite_half_of_wide() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed("漢\x1b[1;2Hx".as_bytes());
+        assert_eq!(line_text(&terminal, 0), " x");
+    }
+
+    #[test]
+    pub fn combining_marks_join() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed("e\u{301}\u{1F1EC}\u{1F1E7}!".as_bytes());
+        assert_eq!(terminal.screen().cell(0, 0).text, "e\u{301}");
+        assert_eq!(terminal.screen().cell(1, 0).text, "\u{1F1EC}\u{1F1E7}");
+        assert_eq!(terminal.screen().cell(3, 0).text, "!");
+        assert_eq!(terminal.cursor.get(), (4, 0));
+    }
+
+    #[test]
+    pub fn tab_stops() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 20);
+
+        terminal.feed(b"abcdefghij\r\ta\tb");
+        assert_eq!(line_text(&terminal, 0), "abcdefghaj      b");
+        assert_eq!(terminal.cursor.get(), (17, 0));
+
+        terminal.feed(b"\t\t");
+        assert_eq!(terminal.cursor.get(), (19, 0));
+
+        terminal.feed(b"\x1b[2Z");
+        assert_eq!(terminal.cursor.get(), (8, 0));
+    }
+
+    #[test]
+    pub fn set_and_clear_tab_stops() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 20);
+
+        terminal.feed(b"\x1b[3g\x1b[1;4H\x1bH\r\t");
+        assert_eq!(terminal.cursor.get(), (3, 0));
+
+        terminal.feed(b"\x1b[0g\r\x1b[I");
+        assert_eq!(terminal.cursor.get(), (19, 0));
+
+        terminal.resize(3, 30);
+        terminal.feed(b"\r\x1b[2I");
+        assert_eq!(terminal.cursor.get(), (16, 0));
+    }
+
+    #[test]
+    pub fn save_restore_cursor() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[3;5H\x1b[1m\x1b7\x1b[m\x1b[10;10H\x1b8");
+        assert_eq!(terminal.cursor.get(), (4, 2));
+        assert!(terminal.renderer.attr().bold());
+
+        terminal.feed(b"\x1b[1;1H\x1b[s\x1b[5;5H\x1b[u");
+        assert_eq!(terminal.cursor.get(), (0, 0));
+    }
+
+    #[test]
+    pub fn saved_cursor_per_screen() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[2;2H\x1b7\x1b[?47h\x1b[4;4H\x1b7\x1b[?47l\x1b8");
+        assert_eq!(terminal.cursor.get(), (1, 1));
+
+        terminal.feed(b"\x1b[?47h\x1b8");
+        assert_eq!(terminal.cursor.get(), (3, 3));
+    }
+
+    #[test]
+    pub fn restore_without_save() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[4m\x1b[5;5H\x1b8");
+        assert_eq!(terminal.cursor.get(), (0, 0));
+        assert!(!terminal.renderer.attr().single_underline());
+    }
+
+    #[test]
+    pub fn device_attributes() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[c\x1b[>c\x1b[5n");
+        assert_eq!(
+            terminal.drain_responses(),
+            vec!["\x1b[?62;4;22c", "\x1b[>1;10;0c", "\x1b[0n"]
+        );
+        assert!(terminal.drain_responses().is_empty());
+    }
+
+    #[test]
+    pub fn cursor_position_report() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[5;12H\x1b[6n");
+        assert_eq!(terminal.drain_responses(), vec!["\x1b[5;12R"]);
+    }
+
+    #[test]
+    pub fn mode_reports() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[?2004h\x1b[?2004$p\x1b[?1049$p\x1b[?u");
+        assert_eq!(
+            terminal.drain_responses(),
+            vec!["\x1b[?2004;1$y", "\x1b[?1049;2$y", "\x1b[?0u"]
+        );
+    }
+
+    #[test]
+    pub fn dec_line_drawing() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b(0lqk\x1b(Bx");
+        assert_eq!(line_text(&terminal, 0), "┌─┐x");
+    }
+
+    #[test]
+    pub fn shift_in_shift_out() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b)0a\x0ex\x0fx\x1b)A\x0e#");
+        assert_eq!(line_text(&terminal, 0), "a│x£");
+    }
+
+    #[test]
+    pub fn single_shift() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b*0\x1bNqq");
+        assert_eq!(line_text(&terminal, 0), "─q");
+    }
+
+    #[test]
+    pub fn saved_charsets() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b(0\x1b7\x1b(B\x1b8q");
+        assert_eq!(line_text(&terminal, 0), "─");
+    }
+
+    #[test]
+    pub fn autowrap_pending() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 5);
+
+        terminal.feed(b"abcde");
+        assert_eq!(terminal.cursor.get(), (4, 0));
+        assert!(terminal.cursor.wrap_pending);
+
+        // A full line followed by a newline doesn't leave an empty line
+        terminal.feed(b"\r\nx");
+        assert_eq!(line_text(&terminal, 1), "x");
+
+        terminal.feed(b"\x1b[2;1Hvwxyz!");
+        assert_eq!(line_text(&terminal, 1), "vwxyz");
+        assert_eq!(line_text(&terminal, 2), "!");
+    }
+
+    #[test]
+    pub fn autowrap_off() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 5);
+
+        terminal.feed(b"\x1b[?7$p\x1b[?7labcdefg\x1b[?7$p");
+        assert_eq!(line_text(&terminal, 0), "abcdg");
+        assert_eq!(line_text(&terminal, 1), "");
+        assert_eq!(terminal.cursor.get(), (4, 0));
+        assert!(!terminal.cursor.wrap_pending);
+        assert_eq!(terminal.drain_responses(), vec!["\x1b[?7;1$y", "\x1b[?7;2$y"]);
+    }
+
+    #[test]
+    pub fn origin_mode() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(6, 10);
+
+        terminal.feed(b"\x1b[2;4r\x1b[?6h");
+        assert_eq!(terminal.cursor.get(), (0, 1));
+
+        terminal.feed(b"\x1b[2;3H\x1b[6n");
+        assert_eq!(terminal.cursor.get(), (2, 2));
+        assert_eq!(terminal.drain_responses(), vec!["\x1b[2;3R"]);
+
+        terminal.feed(b"\x1b[10B");
+        assert_eq!(terminal.cursor.get(), (2, 3));
+        terminal.feed(b"\x1b[10A");
+        assert_eq!(terminal.cursor.get(), (2, 1));
+
+        // Can't be addressed outside of the margins
+        terminal.feed(b"\x1b[10;1H");
+        assert_eq!(terminal.cursor.get(), (0, 3));
+
+        terminal.feed(b"\x1b[?6l");
+        assert_eq!(terminal.cursor.get(), (0, 0));
+    }
+
+    #[test]
+    pub fn insert_mode() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 5);
+
+        terminal.feed(b"abcd\r\x1b[4hxy");
+        assert_eq!(line_text(&terminal, 0), "xyabc");
+
+        terminal.feed(b"\x1b[4lz");
+        assert_eq!(line_text(&terminal, 0), "xyzbc");
+    }
+
+    #[test]
+    pub fn newline_mode() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b[20ha\nb\x1b[20$p");
+        assert_eq!(line_text(&terminal, 1), "b");
+        assert_eq!(terminal.drain_responses(), vec!["\x1b[20;1$y"]);
+
+        terminal.feed(b"\x1b[20l\nc");
+        assert_eq!(line_text(&terminal, 2), " c");
+    }
+
+    #[test]
+    pub fn alt_screen_1049() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 10);
+
+        terminal.feed(b"$ vim\x1b[?1049h");
+        assert!(terminal.state.alt_screen);
+        assert_eq!(line_text(&terminal, 0), "");
+
+        terminal.feed(b"\x1b[3;3Hbuffer\x1b[?1049l");
+        assert!(!terminal.state.alt_screen);
+        assert_eq!(line_text(&terminal, 0), "$ vim");
+        assert_eq!(terminal.cursor.get(), (5, 0));
+
+        // Entering again starts from a blank screen
+        terminal.feed(b"\x1b[?1049h");
+        assert_eq!(line_text(&terminal, 2), "");
+    }
+
+    #[test]
+    pub fn alt_screen_47_and_1047() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 10);
+
+        terminal.feed(b"\x1b[?47hkept\x1b[?47l\x1b[?47h");
+        assert_eq!(line_text(&terminal, 0), "kept");
+
+        terminal.feed(b"\x1b[?47l\x1b[?1047h\r\nx\x1b[?1047l");
+        assert_eq!(line_text(&terminal, 0), "");
+
+        terminal.feed(b"\x1b[?47h");
+        assert_eq!(line_text(&terminal, 0), "");
+        assert_eq!(line_text(&terminal, 1), "");
+    }
+
+    #[test]
+    pub fn alt_screen_matches_size() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 10);
+        terminal.renderer.alt_screen.set_size(24, 80);
+
+        terminal.feed(b"\x1b[?1049h");
+        assert_eq!((terminal.screen().rows, terminal.screen().cols), (5, 10));
+    }
+
+    #[test]
+    pub fn scrollback_limit() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 10);
+        terminal.set_max_scrollback(5);
+
+        for i in 0..20 {
+            if i == 10 {
+                terminal.feed(b"\x1b]133;A\x07");
+            }
+            if i == 15 {
+                terminal.feed(b"\x1b]1337;SetMark\x07");
+            }
+            terminal.feed(format!("{i}\r\n").as_bytes());
+        }
+
+        assert_eq!(terminal.screen().visible_start(), 5);
+        assert_eq!(terminal.screen().len(), 8);
+        assert_eq!(line_text(&terminal, 0), "18");
+        assert_eq!(terminal.marks, vec![(0, 2)]);
+        assert_eq!(terminal.commands.len(), 1);
+    }
+
+    #[test]
+    pub fn attribute_compaction() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 10);
+        terminal.set_max_scrollback(5);
+
+        // Every line has its own colour, which is freed once the line is trimmed
+        for i in 0..3000 {
+            terminal.feed(format!("\x1b[38;2;{};{};0mx\r\n", i % 256, i / 256).as_bytes());
+        }
+        assert!(terminal.renderer.attrs.len() < 1100);
+
+        let colour = termwiz::color::RgbColor::new_8bpc((2999 % 256) as u8, (2999 / 256) as u8, 0);
+        let cell = terminal.screen().cell(0, 1);
+        assert_eq!(terminal.cell_attr(&cell).get_fg(), termwiz::color::ColorSpec::from(colour));
+    }
+
+    #[test]
+    pub fn clear_scrollback() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 10);
+
+        terminal.feed(b"0\r\n1\r\n2\r\n3\r\n4\r\n5\x1b]1337;SetMark\x07\x1b[3J");
+        assert_eq!(terminal.screen().visible_start(), 0);
+        assert_eq!(terminal.screen().len(), 3);
+        assert_eq!(line_text(&terminal, 0), "3");
+        assert_eq!(terminal.marks, vec![(1, 2)]);
+    }
+
+    #[test]
+    pub fn damaged_lines() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(4, 10);
+
+        terminal.feed(b"a\r\nb\r\nc");
+        let generation = terminal.generation();
+        assert!(terminal.damaged_lines(generation).is_empty());
+
+        terminal.feed(b"\x1b[2;1Hx");
+        assert_eq!(terminal.damaged_lines(generation), vec![1]);
+
+        // Lines moved by a delete are all damaged
+        let generation = terminal.generation();
+        terminal.feed(b"\x1b[1;1H\x1b[M");
+        assert_eq!(terminal.damaged_lines(generation), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    pub fn stable_line_numbers() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(2, 10);
+        terminal.set_max_scrollback(1);
+
+        terminal.feed(b"0\r\n1\r\n2\r\n3");
+        let screen = terminal.screen();
+        assert_eq!(screen.line_number(screen.visible_start()), 2);
+        assert_eq!(screen.line_index(0), None);
+        assert_eq!(screen.line_index(3), Some(2));
+    }
+
+    #[test]
+    pub fn repeat_character() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"a\x1b[3b");
+        assert_eq!(line_text(&terminal, 0), "aaaa");
+    }
+
+    #[test]
+    pub fn sixel_placement() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(3, 10);
+        terminal.set_cell_size(1, 6);
+
+        // A 2x12 image covers two cells each way, scrolling to fit
+        terminal.feed(b"\r\n\r\nab\x1bPq#0;2;0;0;100!2~-!2~\x1b\\");
+        let image = &terminal.screen().images()[0];
+        assert_eq!((image.x, image.line, image.cols, image.rows), (2, 2, 2, 2));
+        assert_eq!(terminal.cursor.get(), (2, 2));
+
+        terminal.feed(b"\r\n\r\n");
+        assert_eq!(terminal.screen().images()[0].line, 2);
+
+        terminal.feed(b"\x1b[2J");
+        assert!(terminal.screen().images().is_empty());
+    }
+
+    #[test]
+    pub fn kitty_transmit_and_place() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.set_cell_size(1, 1);
+
+        // A red pixel, placed at the cursor
+        terminal.feed(b"ab\x1b_Ga=T,f=24,s=1,v=1,i=5;/wAA\x1b\\");
+        assert_eq!(terminal.drain_responses(), vec!["\x1b_Gi=5;OK\x1b\\"]);
+        let placement = &terminal.screen().images()[0];
+        assert_eq!((placement.image_id, placement.x, placement.line), (5, 2, 0));
+        assert_eq!(placement.image.pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(terminal.cursor.get(), (3, 0));
+
+        // Huge sizes are clamped rather than scrolling forever
+        terminal.resize(4, 10);
+        terminal.set_max_scrollback(6);
+        terminal.feed(b"\x1b_Ga=p,i=5,c=4000000000,r=4000000000,q=2\x1b\\");
+        let placement = terminal.screen().images().last().unwrap();
+        assert_eq!((placement.cols, placement.rows), (40, 10));
+
+        terminal.feed(b"\x1b_Ga=p,i=99\x1b\\");
+        assert_eq!(terminal.drain_responses(), vec!["\x1b_Gi=99;ENOENT:no such image\x1b\\"]);
+
+        // Lowercase keeps the image, uppercase frees it
+        terminal.feed(b"\x1b_Ga=d,d=i,i=5\x1b\\");
+        assert!(terminal.screen().images().is_empty());
+        assert!(terminal.images.get(5).is_some());
+        terminal.feed(b"\x1b_Ga=p,i=5,q=2\x1b\\\x1b_Ga=d,d=I,i=5\x1b\\");
+        assert!(terminal.screen().images().is_empty());
+        assert!(terminal.images.get(5).is_none());
+    }
+
+    #[test]
+    pub fn kitty_chunked_and_query() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b_Gf=24,s=2,v=1,i=7,m=1;/wAA\x1b\\");
+        assert!(terminal.images.get(7).is_none());
+        terminal.feed(b"\x1b_Gm=0;AP8A\x1b\\");
+        let image = &terminal.images.get(7).unwrap().image;
+        assert_eq!(image.pixel(1, 0), [0, 255, 0, 255]);
+        assert_eq!(terminal.drain_responses(), vec!["\x1b_Gi=7;OK\x1b\\"]);
+
+        // Queries are answered without storing anything
+        terminal.feed(b"\x1b_Ga=q,i=31,s=1,v=1,f=24;AAAA\x1b\\");
+        terminal.feed(b"\x1b_Ga=q,i=32,s=2,v=2,f=24;AAAA\x1b\\");
+        assert_eq!(
+            terminal.drain_responses(),
+            vec![
+                "\x1b_Gi=31;OK\x1b\\",
+                "\x1b_Gi=32;ENODATA:not enough pixel data for the image size\x1b\\"
+            ]
+        );
+        assert!(terminal.images.get(31).is_none());
+
+        // A chunk for another image drops the transfer that was waiting
+        terminal.feed(b"\x1b_Gf=24,s=2,v=1,i=8,m=1;/wAA\x1b\\\x1b_Gi=9,m=0;AP8A\x1b\\");
+        assert_eq!(
+            terminal.drain_responses(),
+            vec!["\x1b_Gi=8;EINVAL:chunk sent for a different image\x1b\\"]
+        );
+        terminal.feed(b"\x1b_Gm=0;AP8A\x1b\\");
+        assert!(terminal.images.get(8).is_none());
+    }
+
+    #[test]
+    pub fn kitty_placeholders() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.set_cell_size(1, 1);
+
+        terminal.feed(b"\x1b_Ga=t,f=24,s=2,v=1,i=1,q=2;/wAAAP8A\x1b\\");
+        // Image 1 in the foreground, the second cell carries on from the first
+        terminal.feed("x\x1b[38;5;1m\u{10EEEE}\u{0305}\u{0305}\u{10EEEE}".as_bytes());
+
+        let runs = terminal.placeholders(0);
+        assert_eq!(runs.len(), 1);
+        let run = &runs[0];
+        assert_eq!((run.image_id, run.x, run.width, run.row, run.col), (1, 1, 2, 0, 0));
+        assert_eq!((run.cols, run.rows), (2, 1));
+    }
+
+    #[test]
+    pub fn iterm_inline_image() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.set_cell_size(1, 1);
+        let png = super::image::TerminalImage::new(2, 2, vec![255; 16]);
+        let data = png.data_url().trim_start_matches("data:image/png;base64,");
+
+        let file = "\x1b]1337;File=inline=1;width=4px;height=3px;preserveAspectRatio=0";
+        terminal.feed(format!("{file}:{data}\x07").as_bytes());
+        let placement = &terminal.screen().images()[0];
+        assert_eq!((placement.width, placement.height), (4, 3));
+        assert_eq!((placement.cols, placement.rows), (4, 3));
+        assert_eq!(terminal.cursor.get(), (4, 2));
+
+        // Keeping the aspect ratio fits the image inside the box
+        let file = "\x1b]1337;File=inline=1;width=4px;height=3px";
+        terminal.feed(format!("{file}:{data}\x07").as_bytes());
+        let placement = &terminal.screen().images()[1];
+        assert_eq!((placement.width, placement.height), (3, 3));
+
+        // Sizes far past the screen are clamped, width to four screens, height to the scrollback
+        terminal.resize(4, 10);
+        terminal.set_max_scrollback(6);
+        let file = "\x1b]1337;File=inline=1;width=4000000000;height=99999999999px";
+        let file = format!("{file};preserveAspectRatio=0");
+        terminal.feed(format!("{file}:{data}\x07").as_bytes());
+        let placement = terminal.screen().images().last().unwrap();
+        assert_eq!((placement.width, placement.height), (40, 10));
+        assert_eq!((placement.cols, placement.rows), (40, 10));
+    }
+
+    #[test]
+    pub fn osc8_hyperlinks() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(2, 8);
+
+        // A reset inside the link keeps it, the empty OSC 8 ends it
+        terminal.feed(b"\x1b]8;id=a;http://example.com\x1b\\a\x1b[0mb\x1b]8;;\x1b\\c");
+        for x in 0..2 {
+            let cell = terminal.screen().cell(x, 0);
+            let link = terminal.cell_attr(&cell).hyperlink().unwrap();
+            assert_eq!(link.uri(), "http://example.com");
+            assert_eq!(link.params().get("id").map(String::as_str), Some("a"));
+        }
+        let cell = terminal.screen().cell(2, 0);
+        assert!(terminal.cell_attr(&cell).hyperlink().is_none());
+    }
+
+    #[test]
+    pub fn implicit_links() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(4, 12);
+        terminal.feed(b"\x1b]7;file:///home/me\x07");
+        let rules = vec![
+            Rule::new(r"\b\w+://\S*[^\s.]", "$0").unwrap(),
+            Rule::new(r"([\w/]+\.rs):(\d+)", "file:$1#$2").unwrap(),
+        ];
+        terminal.set_link_rules(rules, vec![String::from("http")]);
+
+        // The reference wraps onto a second line and is only linked once the line ends
+        terminal.feed(b"at src/lib.rs:12");
+        let link = |terminal: &Terminal, x, y| {
+            let cell = terminal.screen().cell(x, y);
+            terminal.cell_attr(&cell).hyperlink().map(|link| link.uri().to_string())
+        };
+        assert_eq!(link(&terminal, 3, 0), None);
+        terminal.feed(b"\r\nhttp://a.b.\r\n");
+
+        let file = Some("file:///home/me/src/lib.rs#12".to_string());
+        assert_eq!(link(&terminal, 3, 0), file);
+        assert_eq!(link(&terminal, 3, 1), file);
+        assert_eq!(link(&terminal, 2, 0), None);
+        assert_eq!(link(&terminal, 0, 2), Some("http://a.b".to_string()));
+        assert_eq!(link(&terminal, 10, 2), None);
+
+        // Schemes that aren't allowed and files on other machines aren't linked
+        terminal.feed(b"\x1b[2J\x1b[Hsmb://a/b\r\n\x1b]7;file://far/srv\x07a.rs:1\r\n");
+        assert_eq!(link(&terminal, 0, 0), None);
+        assert_eq!(link(&terminal, 0, 1), None);
+    }
+
+    #[test]
+    pub fn colour_osc() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+
+        terminal.feed(b"\x1b]4;1;#ff0000\x1b\\\x1b]4;1;?\x07\x1b]11;?\x07");
+        assert_eq!(
+            terminal.drain_responses(),
+            vec!["\x1b]4;1;rgb:ffff/0000/0000\x1b\\", "\x1b]11;rgb:0000/0000/0000\x1b\\"]
+        );
+
+        // Extra colours carry on to the next dynamic colour
+        terminal.feed(b"\x1b]10;#102030;#405060\x07\x1b]11;?\x07");
+        assert_eq!(terminal.colours.fg, Some([0x10, 0x20, 0x30]));
+        assert_eq!(terminal.drain_responses(), vec!["\x1b]11;rgb:4040/5050/6060\x1b\\"]);
+
+        terminal.feed(b"\x1b]104\x07\x1b]111\x07\x1b]4;1;?\x07");
+        assert_eq!(terminal.colours.bg, None);
+        assert_eq!(terminal.drain_responses(), vec!["\x1b]4;1;rgb:cdcd/0000/0000\x1b\\"]);
+    }
+
+    // Records clipboard calls, None for requests
+    struct ClipboardWindow(Rc<RefCell<Vec<(Clipboard, Option<String>)>>>);
+
+    impl WindowHandler for ClipboardWindow {
+        fn set_clipboard(&mut self, clipboard: Clipboard, text: String) {
+            self.0.borrow_mut().push((clipboard, Some(text)));
+        }
+
+        fn request_clipboard(&mut self, clipboard: Clipboard) {
+            self.0.borrow_mut().push((clipboard, None));
+        }
+    }
+
+    #[test]
+    pub fn osc52_clipboard() {
+        let calls = Rc::default();
+        let mut terminal = Terminal::setup(Box::new(ClipboardWindow(Rc::clone(&calls)))).unwrap();
+
+        terminal.feed(b"\x1b]52;c;aGVsbG8=\x07\x1b]52;p;?\x07\x1b]52;0;aGk=\x07");
+        assert_eq!(
+            *calls.borrow(),
+            vec![(Clipboard::Clipboard, Some("hello".to_string())), (Clipboard::Primary, None)]
+        );
+
+        terminal.clipboard_reply(Clipboard::Primary, "hi");
+        assert_eq!(terminal.drain_responses(), vec!["\x1b]52;p;aGk=\x1b\\"]);
+    }
+
+    #[test]
+    pub fn osc7_working_directory() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.feed(b"\x1b]7;file://remote/home/me/my%20dir\x07");
+        let cwd = terminal.state.cwd.clone().unwrap();
+        assert_eq!((cwd.host.as_str(), cwd.path.as_str()), ("remote", "/home/me/my dir"));
+        assert!(!cwd.is_local());
+
+        // Anything that isn't a file url leaves the last one in place
+        terminal.feed(b"\x1b]7;/tmp\x07\x1b]7;file://localhost\x07");
+        let cwd = terminal.state.cwd.clone().unwrap();
+        assert_eq!(cwd.path, "/");
+        assert!(cwd.is_local());
+    }
+
+    #[test]
+    pub fn command_records() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.resize(5, 10);
+        terminal.feed(b"\x1b]7;file:///tmp\x07\x1b]133;A;aid=7\x07$ \x1b]133;B\x07ls -a\r\n");
+        terminal.feed(b"\x1b]133;C\x07one\r\ntwo\r\n\x1b]133;D;1\x07\x1b]133;A\x07$ ");
+
+        let command = terminal.commands.command(1).unwrap();
+        assert_eq!(command.text(), "ls -a");
+        assert_eq!(command.aid(), Some("7"));
+        assert_eq!(command.exit_code(), Some(1));
+        assert_eq!(command.cwd().unwrap().path, "/tmp");
+        assert!(command.duration().is_some());
+
+        assert_eq!(terminal.command_text(1, CommandZone::Prompt).unwrap(), "$");
+        assert_eq!(terminal.command_text(1, CommandZone::Output).unwrap(), "one\ntwo");
+        assert_eq!(terminal.command_text(2, CommandZone::Input), None);
+        assert_eq!(terminal.command_text(2, CommandZone::Prompt).unwrap(), "$");
+    }
+
+    #[test]
+    pub fn semantic_zones() {
+        let mut terminal = Terminal::setup_no_window().unwrap();
+        terminal.feed(b"\x1b]133;P;k=r\x07>\x1b]133;I\x07ls\r\nout\r\n");
+        terminal.feed(b"\x1b]133;P;k=c\x07.\x1b]133;B\x07a\r\nb");
+
+        let sem = |x: usize, y: usize| {
+            let index = terminal.screen().phys_line(y);
+            let line = terminal.screen().line(index).unwrap();
+            terminal.cell_attr(&line[x]).semantic_type()
+        };
+        assert_eq!(sem(0, 0), SemanticType::Prompt(PromptKind::RightSide));
+        assert_eq!(sem(1, 0), SemanticType::Input(Until::LineEnd));
+        assert_eq!(sem(0, 1), SemanticType::Output);
+        assert_eq!(sem(0, 2), SemanticType::Prompt(PromptKind::Continuation));
+        assert_eq!(sem(1, 2), SemanticType::Input(Until::SemanticMarker));
+        assert_eq!(sem(0, 3), SemanticType::Input(Until::SemanticMarker));
     }
 }
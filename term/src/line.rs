@@ -1,12 +1,16 @@
 use std::ops::{Deref, DerefMut, Index, IndexMut};
 
-use super::cell::Cell;
+use super::cell::{AttrId, Cell, CellText};
 
 #[derive(Clone, Debug, Default)]
 pub struct Line {
     cells: Vec<Cell>,
     pub width: bool,
     pub height: bool,
+    // Soft wrapped into the next line, rather than ending with a line feed
+    pub wrapped: bool,
+    // Screen generation of the last change, stamped by the Screen
+    pub generation: u64,
 }
 
 impl Line {
@@ -15,6 +19,8 @@ impl Line {
             cells: Vec::new(),
             width: false,
             height: false,
+            wrapped: false,
+            generation: 0,
         }
     }
 
@@ -23,6 +29,8 @@ impl Line {
             cells: vec![Cell::default()],
             width: false,
             height: false,
+            wrapped: false,
+            generation: 0,
         }
     }
 
@@ -31,11 +39,73 @@ impl Line {
             cells: vec![cell; amount],
             width: false,
             height: false,
+            wrapped: false,
+            generation: 0,
         }
     }
 
     pub fn set(&mut self, cells: Vec<Cell>) { self.cells = cells; }
 
+    /// Consecutive cells that share attributes, so renderers only look each run up once
+    pub fn runs(&self) -> impl Iterator<Item = (AttrId, &[Cell])> {
+        self.cells.chunk_by(|a, b| a.attr == b.attr).map(|run| (run[0].attr, run))
+    }
+
+    /// Roughly how many bytes the line is using
+    pub fn memory_usage(&self) -> usize {
+        let clusters = self.cells.iter().map(|cell| match &cell.text {
+            CellText::Cluster(text) => std::mem::size_of::<String>() + text.capacity(),
+            CellText::Inline { .. } => 0,
+        });
+        std::mem::size_of::<Line>()
+            + self.cells.capacity() * std::mem::size_of::<Cell>()
+            + clusters.sum::<usize>()
+    }
+
+    /// The screen generation this line last changed in
+    pub fn generation(&self) -> u64 { self.generation }
+
+    /// Blanks the other half of a wide character that x is part of
+    /// Needed before overwriting either half of it
+    pub fn split_wide(&mut self, x: usize) {
+        let Some(cell) = self.cells.get(x) else { return };
+
+        if cell.is_spacer() && x > 0 {
+            self.cells[x - 1] = Cell::default();
+        } else if cell.width() > 1 && x + 1 < self.cells.len() {
+            self.cells[x + 1] = Cell::default();
+        }
+    }
+
+    /// Inserts blank cells at x, shifting the rest of the line right
+    /// Cells shifted past the width of the screen are lost
+    pub fn insert_cells(&mut self, x: usize, amount: usize, blank: Cell, width: usize) {
+        if x >= width {
+            return;
+        }
+        if self.cells.len() < x {
+            self.cells.resize(x, Cell::default());
+        }
+
+        let amount = amount.min(width - x);
+        self.cells.splice(x..x, std::iter::repeat(blank).take(amount));
+        self.cells.truncate(width);
+    }
+
+    /// Deletes cells at x, shifting the rest of the line left
+    /// The end of the line is filled with blank cells
+    pub fn delete_cells(&mut self, x: usize, amount: usize, blank: Cell, width: usize) {
+        if x >= self.cells.len() {
+            return;
+        }
+
+        let amount = amount.min(self.cells.len() - x);
+        self.cells.drain(x..x + amount);
+        if self.cells.len() < width {
+            self.cells.resize(width, blank);
+        }
+    }
+
     pub fn set_width(&mut self, double: bool) { self.width = double }
     pub fn set_height(&mut self, double: bool) { self.height = double }
     pub fn set_double(&mut self, double: bool) {
@@ -0,0 +1,92 @@
+// Implicit hyperlinks, found by matching link rules against finished lines
+// Rules come from the config, termwiz does the matching and expands the link format
+
+use crate::state::WorkingDirectory;
+
+/// Most rows of a wrapped line checked for links when it ends
+pub const MAX_LINK_ROWS: usize = 64;
+
+/// The scheme of a link, lowercased, None if it does not start with one
+pub fn link_scheme(uri: &str) -> Option<String> {
+    let (scheme, _) = uri.split_once(':')?;
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    valid.then(|| scheme.to_ascii_lowercase())
+}
+
+/// If a link may be opened, file links have to be on this machine
+/// and anything else needs one of the allowed schemes
+pub fn link_allowed(uri: &str, schemes: &[String]) -> bool {
+    match link_scheme(uri) {
+        Some(scheme) if scheme == "file" => {
+            let target = uri.split('#').next().unwrap_or(uri);
+            let url = WorkingDirectory::parse(&format!("file:{}", &target["file:".len()..]));
+            url.is_none_or(|url| url.is_local())
+        }
+        Some(scheme) => schemes.iter().any(|allowed| allowed.eq_ignore_ascii_case(&scheme)),
+        None => false,
+    }
+}
+
+/// Where an implicit link points
+/// Rules write file links as file:PATH#LINE:COLUMN, relative paths are joined onto the cwd
+/// and the result is a file url on the cwd's host, with empty parts of the line fragment dropped
+/// Relative paths are left as they are while the cwd is unknown
+pub fn resolve_link(uri: &str, cwd: Option<&WorkingDirectory>) -> String {
+    let Some(target) = uri.strip_prefix("file:").filter(|path| !path.starts_with("//")) else {
+        return uri.to_string();
+    };
+    let (path, position) = target.split_once('#').unwrap_or((target, ""));
+
+    let mut resolved = String::from("file://");
+    if let Some(cwd) = cwd {
+        resolved.push_str(&cwd.host);
+    }
+    if path.starts_with('/') {
+        resolved.push_str(path);
+    } else {
+        let Some(cwd) = cwd else { return uri.to_string() };
+        resolved.push_str(cwd.path.trim_end_matches('/'));
+        resolved.push('/');
+        resolved.push_str(path.trim_start_matches("./"));
+    }
+
+    let position = position.trim_end_matches(':');
+    if !position.is_empty() {
+        resolved.push('#');
+        resolved.push_str(position);
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn resolve_links() {
+        let dir = WorkingDirectory { host: String::new(), path: String::from("/home/me/") };
+        let cwd = Some(&dir);
+        assert_eq!(resolve_link("https://example.com", cwd), "https://example.com");
+        assert_eq!(resolve_link("file:src/lib.rs#12:", cwd), "file:///home/me/src/lib.rs#12");
+        assert_eq!(resolve_link("file:./a.rs#1:2", cwd), "file:///home/me/a.rs#1:2");
+        assert_eq!(resolve_link("file:/usr/bin#", cwd), "file:///usr/bin");
+        assert_eq!(resolve_link("file:a.rs#3:", None), "file:a.rs#3:");
+
+        let remote = WorkingDirectory { host: String::from("far"), path: String::from("/srv") };
+        assert_eq!(resolve_link("file:a.rs#3:", Some(&remote)), "file://far/srv/a.rs#3");
+    }
+
+    #[test]
+    pub fn allowed_links() {
+        let schemes = [String::from("https"), String::from("mailto")];
+        assert!(link_allowed("HTTPS://example.com", &schemes));
+        assert!(link_allowed("mailto:me@example.com", &schemes));
+        assert!(link_allowed("file:///etc/hosts#3", &schemes));
+        assert!(link_allowed("file:src/lib.rs", &schemes));
+        assert!(!link_allowed("file://far/etc/hosts", &schemes));
+        assert!(!link_allowed("javascript:alert(1)", &schemes));
+        assert!(!link_allowed("smb://server/share", &schemes));
+        assert!(!link_allowed("no scheme", &schemes));
+    }
+}
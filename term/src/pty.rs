@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::ops::Deref;
+use std::path::PathBuf;
 
 use async_channel::Sender;
 use portable_pty::{
@@ -10,9 +11,13 @@ use rand::Rng;
 use termwiz::escape::Action;
 use tokio::runtime::Runtime;
 
+use super::shell;
+
 pub struct PseudoTerminalSystem {
     pub pty_system: Box<dyn PtySystem + Send>,
     pub ptys: HashMap<String, PseudoTerminal>, // Hashmap?
+    // Where shell integration scripts are written, None leaves shells as they are
+    shell_integration: Option<PathBuf>,
 }
 
 pub struct PseudoTerminal {
@@ -27,13 +32,35 @@ impl PseudoTerminalSystem {
         PseudoTerminalSystem {
             pty_system: native_pty_system(),
             ptys: HashMap::new(),
+            shell_integration: None,
         }
     }
 
     pub fn len(&self) -> usize { self.ptys.len() }
 
+    /// Turns shell integration on with scripts in dir, or off with None
+    /// The scripts are only written when this changes, not for every shell
+    pub fn set_shell_integration(&mut self, dir: Option<PathBuf>) {
+        if self.shell_integration == dir {
+            return;
+        }
+        if let Some(dir) = &dir {
+            if let Err(err) = shell::install(dir) {
+                log::warn!("Failed to write shell integration scripts: {err}");
+                return;
+            }
+        }
+        self.shell_integration = dir;
+    }
+
     /// Requires a sender to pull data out of it
-    pub fn spawn_new(&mut self, mut startup_command: Option<String>) -> anyhow::Result<String> {
+    /// The shell starts in cwd if it is a directory, otherwise wherever the pty system chooses
+    /// Known shells load the integration scripts when shell_integration is set
+    pub fn spawn_new(
+        &mut self,
+        startup_command: Option<String>,
+        cwd: Option<PathBuf>,
+    ) -> anyhow::Result<String> {
         // Create a new pty
         let pair = self.pty_system.openpty(PtySize {
             rows: 24,
@@ -43,16 +70,21 @@ impl PseudoTerminalSystem {
         })?;
 
         // Spawn a shell into the pty
-        if let Some(ref c) = startup_command {
-            if c.is_empty() {
-                startup_command = None;
+        // The start up command can carry arguments, like bash -l, an empty one is the default shell
+        let mut args = startup_command.as_deref().map(shell::split_command).unwrap_or_default();
+        let shell = if args.is_empty() { Self::default_shell() } else { args.remove(0) };
+        log::info!("Opening shell {:?} {:?}", shell, args);
+
+        let mut cmd = CommandBuilder::new(&shell);
+        if let Some(dir) = &self.shell_integration {
+            if shell::inject(&mut cmd, &shell, &args, dir) {
+                log::info!("Loading shell integration into {shell}");
             }
         }
-
-        let shell = startup_command.unwrap_or(Self::default_shell());
-        log::info!("Opening shell {:?}", shell);
-
-        let cmd = CommandBuilder::new(shell);
+        cmd.args(&args);
+        if let Some(cwd) = cwd.filter(|cwd| cwd.is_dir()) {
+            cmd.cwd(cwd);
+        }
         let child = pair.slave.spawn_command(cmd)?;
 
         // Read and parse output from the pty with reader
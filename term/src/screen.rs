@@ -3,7 +3,8 @@ use std::ops::Range;
 
 use termwiz::escape::csi::Sgr;
 
-use super::cell::{Cell, CellAttributes, Color};
+use super::cell::{AttrId, AttributeTable, Cell, CellAttributes};
+use super::image::ImagePlacement;
 use super::line::Line;
 
 #[derive(Debug)]
@@ -11,19 +12,72 @@ pub struct TerminalRenderer {
     pub screen: Screen,
     pub alt_screen: Screen,
 
-    pub attr: CellAttributes,
+    // The pen, only changed through attr_mut so its id is worked out again
+    attr: CellAttributes,
+    attr_id: Option<AttrId>,
+    // Shared by both screens, cells only store ids into it
+    pub attrs: AttributeTable,
+    // Size the table has to grow to before unused attributes are looked for
+    compact_at: usize,
 }
 
+/// Smallest attribute table that is compacted, below this it isn't worth a pass over the cells
+const MIN_COMPACT: usize = 1024;
+
 impl TerminalRenderer {
     pub fn new(rows: usize, cols: usize) -> TerminalRenderer {
         TerminalRenderer {
             screen: Screen::new(rows, cols, true),
             alt_screen: Screen::new(rows, cols, false),
             attr: CellAttributes::default(),
+            attr_id: None,
+            attrs: AttributeTable::new(),
+            compact_at: MIN_COMPACT,
+        }
+    }
+
+    /// Id of the current attributes, for new cells
+    /// Only interned the first time a cell is printed since the attributes last changed
+    pub fn attr_id(&mut self) -> AttrId {
+        *self.attr_id.get_or_insert_with(|| self.attrs.intern(&self.attr))
+    }
+
+    pub fn attr(&self) -> &CellAttributes { &self.attr }
+
+    pub fn attr_mut(&mut self) -> &mut CellAttributes {
+        self.attr_id = None;
+        &mut self.attr
+    }
+
+    /// Drops attributes no cell uses any more, once lines holding them are gone
+    /// The table has to double in size between passes, so it stays at most twice what is in use
+    pub fn compact_attrs(&mut self) {
+        if self.attrs.len() < self.compact_at {
+            return;
         }
+        let mut used = vec![false; self.attrs.len()];
+        for line in self.screen.cells.iter().chain(&self.alt_screen.cells) {
+            for cell in line.iter() {
+                used[cell.attr.0 as usize] = true;
+            }
+        }
+
+        let ids = self.attrs.retain(&used);
+        for line in self.screen.cells.iter_mut().chain(&mut self.alt_screen.cells) {
+            for cell in line.iter_mut() {
+                cell.attr = ids[cell.attr.0 as usize];
+            }
+        }
+        self.attr_id = None;
+        self.compact_at = (self.attrs.len() * 2).max(MIN_COMPACT);
+    }
+
+    /// Roughly how many bytes both screens and the attributes they share are using
+    pub fn memory_usage(&self) -> usize {
+        self.screen.memory_usage() + self.alt_screen.memory_usage() + self.attrs.memory_usage()
     }
 
-    pub fn reset_attr(&mut self) { self.attr = CellAttributes::default(); }
+    pub fn reset_attr(&mut self) { *self.attr_mut() = CellAttributes::default(); }
 
     pub fn get_screen(&self, alt: bool) -> &Screen {
         if alt {
@@ -42,6 +96,7 @@ impl TerminalRenderer {
     }
 
     pub fn handle_sgr(&mut self, sgr: Sgr) {
+        self.attr_id = None;
         match sgr {
             Sgr::Foreground(f) => self.attr.set_fg(f),
             Sgr::Background(b) => self.attr.set_bg(b),
@@ -56,11 +111,61 @@ impl TerminalRenderer {
             Sgr::Font(font) => self.attr.set_font(font),
             Sgr::Overline(o) => self.attr.set_overline(o),
             Sgr::VerticalAlign(vert_align) => self.attr.set_vert_align(vert_align),
-            Sgr::Reset => self.reset_attr(),
+            // Links are not graphic renditions, so a reset keeps them
+            Sgr::Reset => {
+                let link = self.attr.hyperlink().cloned();
+                self.reset_attr();
+                self.attr.set_hyperlink(link);
+            }
         }
     }
 }
 
+/// Maps physical positions from before a reflow to after it
+#[derive(Debug, Default)]
+pub struct ReflowMap {
+    // For each old line: the new index of its logical line,
+    // its offset within the logical line, and the logical line length
+    lines: Vec<(usize, usize, usize)>,
+    old_len: usize,
+    new_len: usize,
+    cols: usize,
+}
+
+impl ReflowMap {
+    fn set_logical_len(&mut self, start: usize, len: usize) {
+        for line in &mut self.lines[start..] {
+            line.2 = len;
+        }
+    }
+
+    /// New position of a cell
+    pub fn map(&self, x: usize, y: usize) -> (usize, usize) {
+        match self.lines.get(y) {
+            Some(&(start, offset, _)) => {
+                let offset = offset + x;
+                (offset % self.cols, start + offset / self.cols)
+            }
+            None => (x, y - self.old_len + self.new_len),
+        }
+    }
+
+    /// New position of the cursor
+    /// A cursor after the end of a line that now fills the width is left waiting to wrap
+    pub fn map_cursor(&self, x: usize, y: usize) -> (usize, usize) {
+        if let Some(&(start, offset, len)) = self.lines.get(y) {
+            let offset = offset + x;
+            if offset > 0 && offset % self.cols == 0 && offset >= len {
+                return (self.cols, start + offset / self.cols - 1);
+            }
+        }
+        self.map(x, y)
+    }
+}
+
+/// Lines kept above the visible screen, until set from the config
+pub const DEFAULT_SCROLLBACK: usize = 1000;
+
 #[derive(Debug)]
 pub struct Screen {
     pub cells: VecDeque<Line>,
@@ -70,17 +175,26 @@ pub struct Screen {
     pub cols: usize,
 
     scrollback_allowed: bool,
+    // Bumped on every change, lines keep the generation they last changed in
+    generation: u64,
+    // Lines removed from the front of the buffer, keeping line numbers stable
+    dropped_lines: usize,
+    // Images drawn over the cells, anchored by their top line
+    images: Vec<ImagePlacement>,
 }
 
 impl Screen {
     pub fn new(rows: usize, cols: usize, sc_allow: bool) -> Screen {
         Screen {
             cells: VecDeque::new(),
-            max_scrollback: 100,
+            max_scrollback: DEFAULT_SCROLLBACK,
             scrollback_allowed: sc_allow,
             scrollback_offset: 0,
             rows,
             cols,
+            generation: 0,
+            dropped_lines: 0,
+            images: Vec::new(),
         }
     }
 
@@ -90,11 +204,274 @@ impl Screen {
         self.rows = rows;
     }
 
+    /// Empties the screen, used by the alternate screen when entering or leaving it
+    pub fn clear(&mut self) {
+        self.drop_front(self.cells.len());
+        self.scrollback_offset = 0;
+    }
+
+    /// Current generation, which increases every time a line changes
+    pub fn generation(&self) -> u64 { self.generation }
+
+    /// Marks lines in the buffer as changed
+    fn touch(&mut self, lines: std::ops::RangeInclusive<usize>) {
+        self.generation += 1;
+        for line in self.cells.range_mut(lines) {
+            line.generation = self.generation;
+        }
+    }
+
+    /// Generation of the line at an index, 0 if there is no line
+    pub fn line_generation(&self, index: usize) -> u64 {
+        self.cells.get(index).map_or(0, |line| line.generation())
+    }
+
+    /// Indexes into the buffer of lines changed since a generation
+    pub fn damaged_lines(&self, since: u64) -> Vec<usize> {
+        self.cells
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.generation > since)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// A number for the line at an index that doesn't change as lines fall off the front
+    pub fn line_number(&self, index: usize) -> usize { self.dropped_lines + index }
+
+    /// Index into the buffer for a line number, if it hasn't been dropped
+    pub fn line_index(&self, number: usize) -> Option<usize> {
+        number.checked_sub(self.dropped_lines).filter(|index| *index < self.cells.len())
+    }
+
+    /// Removes lines from the front of the buffer
+    fn drop_front(&mut self, amount: usize) -> usize {
+        let amount = amount.min(self.cells.len());
+        self.cells.drain(..amount);
+        self.dropped_lines += amount;
+        self.images.retain(|image| image.line >= amount);
+        for image in self.images.iter_mut() {
+            image.line -= amount;
+        }
+        amount
+    }
+
+    /// Draws an image over the cells, starting at a line in the buffer
+    pub fn place_image(&mut self, placement: ImagePlacement) {
+        let end = placement.line + placement.rows.max(1) - 1;
+        self.ensure_lines(end);
+        self.touch(placement.line..=end);
+        self.images.push(placement);
+    }
+
+    /// Every image on the screen, including scrollback
+    pub fn images(&self) -> &[ImagePlacement] { &self.images }
+
+    /// Images whose top is on the line at an index
+    pub fn images_at(&self, index: usize) -> impl Iterator<Item = &ImagePlacement> {
+        self.images.iter().filter(move |image| image.line == index)
+    }
+
+    /// Removes any image covering part of a block within the visible screen
+    pub fn erase_images(&mut self, lines: Range<usize>, columns: Range<usize>) {
+        let lines = self.phys_line(lines.start)..self.phys_line(lines.end);
+        self.remove_images(|image| image.overlaps(&lines, &columns));
+    }
+
+    /// Removes the images a filter picks, returning them
+    pub fn remove_images(
+        &mut self,
+        remove: impl Fn(&ImagePlacement) -> bool,
+    ) -> Vec<ImagePlacement> {
+        let (removed, kept) = std::mem::take(&mut self.images)
+            .into_iter()
+            .partition::<Vec<_>, _>(|image| remove(image));
+        self.images = kept;
+        for image in &removed {
+            self.touch(image.line..=image.line);
+        }
+        removed
+    }
+
+    /// Moves images with their lines when part of the buffer shifts
+    /// Images moved out of the range are dropped
+    fn shift_images(&mut self, range: std::ops::RangeInclusive<usize>, shift: isize) {
+        self.images.retain_mut(|image| {
+            if !range.contains(&image.line) {
+                return true;
+            }
+            let line = image.line as isize + shift;
+            image.line = line.max(0) as usize;
+            line >= *range.start() as isize && line <= *range.end() as isize
+        });
+    }
+
+    /// Rewraps soft-wrapped lines to fit a new width
+    /// The top visible line stays at the top unless the anchor would end up off screen
+    pub fn reflow(&mut self, cols: usize, anchor: (usize, usize)) -> ReflowMap {
+        let mut map = ReflowMap {
+            lines: Vec::with_capacity(self.cells.len()),
+            old_len: self.cells.len(),
+            new_len: 0,
+            cols,
+        };
+        let mut reflowed = VecDeque::new();
+        let mut logical = Vec::new();
+        let mut logical_start = 0;
+
+        for mut line in self.cells.drain(..) {
+            let wrapped = line.wrapped;
+            map.lines.push((reflowed.len(), logical.len(), 0));
+            logical.append(&mut line);
+
+            if !wrapped {
+                let len = Self::rewrap(&mut reflowed, std::mem::take(&mut logical), cols);
+                map.set_logical_len(logical_start, len);
+                logical_start = map.lines.len();
+            }
+        }
+
+        if !logical.is_empty() {
+            let len = Self::rewrap(&mut reflowed, logical, cols);
+            map.set_logical_len(logical_start, len);
+        }
+
+        map.new_len = reflowed.len();
+        self.cells = reflowed;
+        for image in self.images.iter_mut() {
+            image.line = map.map(0, image.line).1;
+        }
+        self.cols = cols;
+        if !self.cells.is_empty() {
+            self.touch(0..=self.cells.len() - 1);
+        }
+
+        let top = map.map(0, self.scrollback_offset).1;
+        let anchor = map.map_cursor(anchor.0, anchor.1).1;
+        self.scrollback_offset = top.max((anchor + 1).saturating_sub(self.rows));
+        map
+    }
+
+    /// Splits a logical line into lines of a certain width, returning its trimmed length
+    fn rewrap(lines: &mut VecDeque<Line>, mut cells: Vec<Cell>, cols: usize) -> usize {
+        while cells.last() == Some(&Cell::default()) {
+            cells.pop();
+        }
+
+        if cells.is_empty() {
+            lines.push_back(Line::with_one());
+            return 0;
+        }
+
+        let mut start = 0;
+        while start < cells.len() {
+            let mut end = (start + cols).min(cells.len());
+            // Never split a wide character from its spacer
+            if end < cells.len() && cells[end].is_spacer() && end - start > 1 {
+                end -= 1;
+            }
+
+            let mut line = Line::new();
+            line.set(cells[start..end].to_vec());
+            line.wrapped = end < cells.len();
+            lines.push_back(line);
+            start = end;
+        }
+
+        cells.len()
+    }
+
+    /// Moves the visible screen down without adding any lines
+    /// Lines are dropped instead if the screen has no scrollback
+    pub fn shift_visible(&mut self, amount: usize) {
+        if self.scrollback_allowed {
+            self.scrollback_offset += amount;
+        } else {
+            self.drop_front(amount);
+        }
+    }
+
     /// Scrolls a line out of the visible screen
     pub fn scrollback(&mut self) { self.scrollback_offset += 1; }
 
-    /// Clears scrollback
-    pub fn clear_scrollback(&mut self) { todo!(); }
+    /// Scrolls the lines between top and bottom (inclusive) up by amount
+    /// A full screen scroll keeps the lines as scrollback if the screen allows it,
+    /// otherwise lines leaving the top of the region are dropped
+    pub fn scroll_up(&mut self, top: usize, bottom: usize, amount: usize, blank: Cell) {
+        let amount = amount.min(bottom + 1 - top);
+
+        if top == 0 && bottom + 1 >= self.rows && self.scrollback_allowed {
+            self.ensure_lines(self.phys_line(bottom));
+            self.scrollback_offset += amount;
+            for y in bottom + 1 - amount..=bottom {
+                *self.mut_line(y) = Line::repeat(blank.clone(), self.cols);
+            }
+            return;
+        }
+
+        self.delete_lines(top, bottom, amount, blank);
+    }
+
+    /// Scrolls the lines between top and bottom (inclusive) down by amount
+    /// Lines pushed past the bottom of the region are dropped
+    pub fn scroll_down(&mut self, top: usize, bottom: usize, amount: usize, blank: Cell) {
+        self.insert_lines(top, bottom, amount, blank);
+    }
+
+    /// Removes lines at top, pulling up the lines below it until bottom
+    /// Blank lines are inserted at the bottom to fill the gap
+    pub fn delete_lines(&mut self, top: usize, bottom: usize, amount: usize, blank: Cell) {
+        let amount = amount.min(bottom + 1 - top);
+        let start = self.phys_line(top);
+        let end = self.phys_line(bottom);
+        self.ensure_lines(end);
+
+        for _ in 0..amount {
+            self.cells.remove(start);
+            self.cells.insert(end, Line::repeat(blank.clone(), self.cols));
+        }
+        self.shift_images(start..=end, -(amount as isize));
+        self.touch(start..=end);
+    }
+
+    /// Inserts blank lines at top, pushing the lines below it down
+    /// Lines pushed past bottom are dropped
+    pub fn insert_lines(&mut self, top: usize, bottom: usize, amount: usize, blank: Cell) {
+        let amount = amount.min(bottom + 1 - top);
+        let start = self.phys_line(top);
+        let end = self.phys_line(bottom);
+        self.ensure_lines(end);
+
+        for _ in 0..amount {
+            self.cells.remove(end);
+            self.cells.insert(start, Line::repeat(blank.clone(), self.cols));
+        }
+        self.shift_images(start..=end, amount as isize);
+        self.touch(start..=end);
+    }
+
+    /// Clears scrollback, leaving only the visible screen
+    /// Returns how many lines were dropped from the front of the buffer
+    pub fn clear_scrollback(&mut self) -> usize {
+        let dropped = self.drop_front(self.scrollback_offset);
+        self.scrollback_offset = 0;
+        dropped
+    }
+
+    /// Sets how many lines are kept above the visible screen
+    pub fn set_max_scrollback(&mut self, max_scrollback: usize) {
+        self.max_scrollback = max_scrollback;
+    }
+
+    pub fn max_scrollback(&self) -> usize { self.max_scrollback }
+
+    /// Drops the oldest lines once the scrollback is over its limit
+    /// Returns how many lines were dropped from the front of the buffer
+    pub fn trim_scrollback(&mut self) -> usize {
+        let dropped = self.drop_front(self.scrollback_offset.saturating_sub(self.max_scrollback));
+        self.scrollback_offset -= dropped;
+        dropped
+    }
 
     /// If the screen has the ability to use scrollback
     pub fn can_scroll(&self) -> bool { self.scrollback_allowed }
@@ -105,20 +482,29 @@ impl Screen {
         let cursor_y = self.visible_start() + cursor_y;
         self.ensure_lines(cursor_y);
 
-        if cursor_x >= self.cells[cursor_y].len() {
-            let extend_amount = cursor_x - self.cells[cursor_y].len();
-            self.cells[cursor_y].extend(vec![Cell::default(); extend_amount + 1])
+        let end = cursor_x + cell.width().max(1);
+        if end > self.cells[cursor_y].len() {
+            let extend_amount = end - self.cells[cursor_y].len();
+            self.cells[cursor_y].extend(vec![Cell::default(); extend_amount])
         }
 
-        // TODO: add extra if cursor out of index
-        self.cells[cursor_y][cursor_x] = cell;
+        self.touch(cursor_y..=cursor_y);
+        let line = &mut self.cells[cursor_y];
+        line.split_wide(cursor_x);
+        if cell.width() > 1 {
+            line.split_wide(cursor_x + 1);
+            line[cursor_x + 1] = Cell::spacer(cell.attr);
+        }
+        line[cursor_x] = cell;
     }
 
     /// Extends the cell lines if there are not enough
     pub fn ensure_lines(&mut self, index: usize) {
         if index >= self.cells.len() {
+            let start = self.cells.len();
             let extend_amount = index - &self.cells.len();
             self.cells.extend(vec![Line::with_one(); extend_amount + 1]);
+            self.touch(start..=index);
         }
     }
 
@@ -137,7 +523,7 @@ impl Screen {
     }
 
     /// Erases scrollback and visible screen
-    pub fn erase_all(&mut self) { self.cells = VecDeque::new(); }
+    pub fn erase_all(&mut self) { self.drop_front(self.cells.len()); }
 
     /// Length of the visible screen
     pub fn len(&self) -> usize { self.cells.len() }
@@ -146,42 +532,67 @@ impl Screen {
     pub fn mut_line(&mut self, index: usize) -> &mut Line {
         let vis_index = self.visible_start() + index;
         self.ensure_lines(vis_index);
+        self.touch(vis_index..=vis_index);
         &mut self.cells[vis_index]
     }
 
     pub fn phys_line(&self, index: usize) -> usize { self.visible_start() + index }
 
+    /// Text between two physical positions, the end is excluded and None is the end of the buffer
+    /// Soft wrapped lines are joined, other lines have trailing blanks trimmed and end with a newline
+    pub fn text_between(&self, start: (usize, usize), end: Option<(usize, usize)>) -> String {
+        let (end_x, end_y) = end.unwrap_or((0, self.cells.len()));
+        let mut text = String::new();
+        for y in start.1..=end_y {
+            let Some(line) = self.cells.get(y) else { break };
+            let from = if y == start.1 { start.0 } else { 0 };
+            let to = if y == end_y { end_x } else { line.len() };
+            let row = line.iter().take(to).skip(from).map(|cell| cell.text.as_str());
+            let row = row.collect::<String>();
+
+            if line.wrapped && y != end_y {
+                text.push_str(&row);
+            } else {
+                text.push_str(row.trim_end());
+                if y != end_y {
+                    text.push('\n');
+                }
+            }
+        }
+        text.truncate(text.trim_end_matches('\n').len());
+        text
+    }
+
+    /// Mutable reference to a line within the screen, marking it as changed
+    pub fn line_mut(&mut self, index: usize) -> Option<&mut Line> {
+        if index >= self.cells.len() {
+            return None;
+        }
+        self.touch(index..=index);
+        self.cells.get_mut(index)
+    }
+
     /// Reference to a line within the screen
     pub fn line(&self, index: usize) -> Option<&Line> {
         //let vis_index = self.visible_start() + index;
         self.cells.get(index)
     }
 
-    /// Pushes a new line onto the screen
-    pub fn new_line(&mut self) {
-        self.cells.push_back(Line::with_one());
-        let len = self.cells.len();
-        if len > self.max_scrollback {
-            self.cells.drain(..len - self.max_scrollback);
-        }
-    }
-
     /// Sets the value of a Line on the visible screen
     pub fn set_line(&mut self, index: usize, line: Vec<Cell>) {
         let vis_index = self.visible_start() + index;
+        self.touch(vis_index..=vis_index);
         self.cells[vis_index].set(line);
     }
 
     /// The index at which the visible screen starts in the scrollback buffer
     pub fn visible_start(&self) -> usize { self.scrollback_offset }
 
-    /// Roughly how much memory the screen is using
-    pub fn memory_usage(&self) -> (usize, usize, usize, usize) {
-        let cell_size = std::mem::size_of::<Cell>();
-        let attr_size = std::mem::size_of::<CellAttributes>();
-        let color_size = std::mem::size_of::<Color>();
-
-        let cells = self.cells.iter().fold(0, |acc, line| acc + line.len());
-        (cells, cell_size, attr_size, color_size)
+    /// Roughly how many bytes the screen is using, including scrollback
+    pub fn memory_usage(&self) -> usize {
+        let spare_lines = self.cells.capacity() - self.cells.len();
+        let lines = self.cells.iter().map(Line::memory_usage).sum::<usize>();
+        let images = self.images.iter().map(|image| image.image.memory_usage()).sum::<usize>();
+        std::mem::size_of::<Screen>() + spare_lines * std::mem::size_of::<Line>() + lines + images
     }
 }
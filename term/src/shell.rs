@@ -0,0 +1,176 @@
+// Shell integration, loaded into shells PreTTY starts so prompts and commands are marked
+// The scripts are built in and written out to a directory the shell can read them from
+
+use std::io;
+use std::path::Path;
+
+use portable_pty::CommandBuilder;
+
+/// Every integration file, by its path within the directory
+const SCRIPTS: [(&str, &str); 6] = [
+    ("pretty.bash", include_str!("../../scripts/pretty.bash")),
+    ("pretty.zsh", include_str!("../../scripts/pretty.zsh")),
+    ("pretty.fish", include_str!("../../scripts/pretty.fish")),
+    ("zsh/.zshenv", include_str!("../../scripts/zsh/.zshenv")),
+    ("zsh/.zprofile", include_str!("../../scripts/zsh/.zprofile")),
+    ("zsh/.zshrc", include_str!("../../scripts/zsh/.zshrc")),
+];
+
+/// Shells there are integration scripts for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// Works the shell out from its program, so /usr/bin/zsh and zsh-5.9 are both zsh
+    pub fn detect(program: &str) -> Option<Shell> {
+        let name = Path::new(program).file_name()?.to_str()?;
+        match name.trim_end_matches(".exe").split('-').next()? {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a command into its program and arguments, at whitespace outside of quotes
+/// Quotes are removed, there are no escapes so Windows paths keep their backslashes
+pub fn split_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = None::<String>;
+    let mut quote = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (_, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// If bash is going to be interactive and read its startup files, rather than run a command
+/// or script or being told which files to read
+fn bash_reads_startup_files(args: &[String]) -> bool {
+    args.iter().all(|arg| match arg.strip_prefix("--") {
+        Some(long) => !["norc", "noprofile", "rcfile", "init-file", "posix"].contains(&long),
+        None => arg.starts_with('-') && !arg.contains('c'),
+    })
+}
+
+/// Writes the scripts into dir, only replacing ones that are missing or from another version
+pub fn install(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir.join("zsh"))?;
+    for (name, script) in SCRIPTS {
+        let path = dir.join(name);
+        if std::fs::read(&path).ok().as_deref() != Some(script.as_bytes()) {
+            std::fs::write(path, script)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets up a command to load the integration, without touching the user's dotfiles
+/// Bash gets the script as its ENV in posix mode, zsh gets a ZDOTDIR that loads the user's
+/// files first and fish sources it once its config has run
+/// Has to be called before the command's own arguments are added,
+/// and the scripts have to be installed into dir already
+/// Returns false for shells there aren't scripts for, or bash that isn't interactive
+pub fn inject(cmd: &mut CommandBuilder, program: &str, args: &[String], dir: &Path) -> bool {
+    let Some(shell) = Shell::detect(program) else { return false };
+
+    match shell {
+        // In posix mode bash reads ENV instead of its startup files, even for login shells,
+        // so the script can turn posix mode off and load whichever files bash would have
+        Shell::Bash if bash_reads_startup_files(args) => {
+            cmd.arg("--posix");
+            if let Some(env) = std::env::var_os("ENV") {
+                cmd.env("PRETTY_USER_ENV", env);
+            }
+            cmd.env("ENV", dir.join("pretty.bash"));
+            // Posix mode keeps history in ~/.sh_history instead
+            if let (None, Some(home)) = (std::env::var_os("HISTFILE"), std::env::var_os("HOME")) {
+                cmd.env("HISTFILE", Path::new(&home).join(".bash_history"));
+                cmd.env("PRETTY_BASH_UNEXPORT_HISTFILE", "1");
+            }
+            cmd.env("PRETTY_BASH_INJECT", "1");
+        }
+        Shell::Bash => return false,
+        Shell::Zsh => {
+            if let Some(zdotdir) = std::env::var_os("ZDOTDIR") {
+                cmd.env("PRETTY_USER_ZDOTDIR", zdotdir);
+            }
+            cmd.env("ZDOTDIR", dir.join("zsh"));
+        }
+        Shell::Fish => {
+            let path = dir.join("pretty.fish").to_string_lossy().replace('\\', "\\\\");
+            cmd.arg("--init-command");
+            cmd.arg(format!("source '{}'", path.replace('\'', "\\'")));
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn detect_shells() {
+        assert_eq!(Shell::detect("/usr/bin/zsh"), Some(Shell::Zsh));
+        assert_eq!(Shell::detect("bash"), Some(Shell::Bash));
+        assert_eq!(Shell::detect("/opt/fish-3.7/bin/fish"), Some(Shell::Fish));
+        assert_eq!(Shell::detect("zsh-5.9"), Some(Shell::Zsh));
+        assert_eq!(Shell::detect("powershell.exe"), None);
+        assert_eq!(Shell::detect("/bin/sh"), None);
+    }
+
+    #[test]
+    pub fn split_commands() {
+        assert_eq!(split_command("bash -l"), ["bash", "-l"]);
+        assert_eq!(split_command("  fish  "), ["fish"]);
+        assert_eq!(
+            split_command(r#""C:\Program Files\pwsh.exe" -NoLogo"#),
+            [r"C:\Program Files\pwsh.exe", "-NoLogo"]
+        );
+        assert_eq!(split_command("zsh -c 'echo \"hi\"' ''"), ["zsh", "-c", "echo \"hi\"", ""]);
+        assert!(split_command("").is_empty());
+    }
+
+    #[test]
+    pub fn bash_startup_files() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(bash_reads_startup_files(&args(&[])));
+        assert!(bash_reads_startup_files(&args(&["-l"])));
+        assert!(bash_reads_startup_files(&args(&["--login", "-i"])));
+        assert!(!bash_reads_startup_files(&args(&["-c", "ls"])));
+        assert!(!bash_reads_startup_files(&args(&["-lc", "ls"])));
+        assert!(!bash_reads_startup_files(&args(&["script.sh"])));
+        assert!(!bash_reads_startup_files(&args(&["--rcfile", "rc"])));
+    }
+
+    #[test]
+    pub fn install_scripts() {
+        let dir = std::env::temp_dir().join(format!("pretty-shell-{}", std::process::id()));
+        install(&dir).unwrap();
+        std::fs::write(dir.join("pretty.zsh"), "old").unwrap();
+
+        // Only changed scripts are written again, so unchanged ones keep their times
+        let bash = dir.join("pretty.bash");
+        let modified = std::fs::metadata(&bash).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        install(&dir).unwrap();
+        assert_eq!(std::fs::metadata(&bash).unwrap().modified().unwrap(), modified);
+        assert_eq!(std::fs::read_to_string(dir.join("pretty.zsh")).unwrap(), SCRIPTS[1].1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
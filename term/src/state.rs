@@ -1,16 +1,80 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
+use gethostname::gethostname;
 use log::info;
 use num_traits::cast::ToPrimitive;
 use termwiz::escape::csi::{
-    DecPrivateMode, DecPrivateModeCode, Keyboard, KittyKeyboardMode, Mode, TerminalMode, XtermKeyModifierResource
+    DecPrivateMode, DecPrivateModeCode, Keyboard, KittyKeyboardMode, Mode, TerminalMode, TerminalModeCode, XtermKeyModifierResource
 };
 use termwiz::escape::DeviceControlMode;
 
+use super::charset::Charset;
+
+/// Top and bottom margins set by DECSTBM
+/// Both are zero-based and inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrollRegion {
+    pub top: usize,
+    pub bottom: usize,
+}
+
+/// Working directory reported with OSC 7, sent as a file://host/path url
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WorkingDirectory {
+    pub host: String,
+    pub path: String,
+}
+
+impl WorkingDirectory {
+    /// Decodes a file url, None if it isn't one
+    pub fn parse(url: &str) -> Option<WorkingDirectory> {
+        let rest = url.strip_prefix("file://")?;
+        let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let path = if path.is_empty() { "/" } else { path };
+        Some(WorkingDirectory { host: percent_decode(host), path: percent_decode(path) })
+    }
+
+    /// If the directory is on this machine, so new shells can start in it
+    pub fn is_local(&self) -> bool {
+        self.host.is_empty() || self.host == "localhost" || gethostname() == *self.host
+    }
+}
+
+/// Decodes %XX escapes, leaving anything that isn't a valid escape as it is
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    let digit = |i: usize| bytes.get(i).and_then(|&byte| (byte as char).to_digit(16));
+    while i < bytes.len() {
+        match (bytes[i], digit(i + 1), digit(i + 2)) {
+            (b'%', Some(high), Some(low)) => {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 // TODO: bitfield? may not be nessecary
 #[derive(Debug, Default)]
 pub struct TerminalState {
-    pub cwd: String,
+    // None until the shell reports it with OSC 7
+    pub cwd: Option<WorkingDirectory>,
+    // None if the scroll region is the whole screen
+    pub scroll_region: Option<ScrollRegion>,
+    // Columns with a tab stop set
+    pub tab_stops: BTreeSet<usize>,
+    // G0-G3 designations, and which one is shifted into GL
+    pub charsets: [Charset; 4],
+    pub shift: usize,
+    // Set by SS2 and SS3 for the next char only
+    pub single_shift: Option<usize>,
     // dec private
     pub dec_modes: HashMap<u16, bool>,
     dec_saves: HashMap<u16, bool>,
@@ -35,9 +99,12 @@ macro_rules! inner_mode {
 
 impl TerminalState {
     pub fn new() -> TerminalState {
-        TerminalState {
+        let mut state = TerminalState {
             ..Default::default()
-        }
+        };
+        // Autowrap is on unless an application turns it off
+        state.dec_modes.insert(DecPrivateModeCode::AutoWrap.to_u16().unwrap(), true);
+        state
     }
 
     pub fn dec_mode(&self, code: DecPrivateModeCode) -> bool {
@@ -47,6 +114,30 @@ impl TerminalState {
             .unwrap_or(&false)
     }
 
+    /// If a dec private mode is on, including modes not stored in dec_modes
+    pub fn dec_mode_set(&self, code: DecPrivateModeCode) -> bool {
+        use termwiz::escape::csi::DecPrivateModeCode::*;
+        match code {
+            BracketedPaste => self.bracketed_paste,
+            EnableAlternateScreen | OptEnableAlternateScreen | ClearAndEnableAlternateScreen => {
+                self.alt_screen
+            }
+            ShowCursor => self.show_cursor,
+            _ => self.dec_mode(code),
+        }
+    }
+
+    /// If a terminal mode is on
+    pub fn mode(&self, code: u16) -> bool { *self.modes.get(&code).unwrap_or(&false) }
+
+    /// IRM, printed chars shift the rest of the line right
+    pub fn insert_mode(&self) -> bool { self.mode(TerminalModeCode::Insert.to_u16().unwrap()) }
+
+    /// LNM, line feeds also return the carriage
+    pub fn newline_mode(&self) -> bool {
+        self.mode(TerminalModeCode::AutomaticNewline.to_u16().unwrap())
+    }
+
     pub fn dec_save(&self, code: DecPrivateModeCode) -> bool {
         *self
             .dec_saves
@@ -54,6 +145,33 @@ impl TerminalState {
             .unwrap_or(&false)
     }
 
+    /// Resets tab stops to every 8 columns
+    pub fn reset_tab_stops(&mut self, cols: usize) {
+        self.tab_stops = (8..cols).step_by(8).collect();
+    }
+
+    pub fn set_tab_stop(&mut self, x: usize) { self.tab_stops.insert(x); }
+
+    pub fn clear_tab_stop(&mut self, x: usize) { self.tab_stops.remove(&x); }
+
+    pub fn clear_tab_stops(&mut self) { self.tab_stops.clear(); }
+
+    /// The first tab stop after x
+    pub fn next_tab_stop(&self, x: usize) -> Option<usize> {
+        self.tab_stops.range(x + 1..).next().copied()
+    }
+
+    /// The last tab stop before x
+    pub fn prev_tab_stop(&self, x: usize) -> Option<usize> {
+        self.tab_stops.range(..x).next_back().copied()
+    }
+
+    /// Translates a printed char through the active charset
+    pub fn translate(&mut self, c: char) -> char {
+        let index = self.single_shift.take().unwrap_or(self.shift);
+        self.charsets[index].map(c)
+    }
+
     pub fn device_control(&mut self, device_command: DeviceControlMode) {
         match device_command {
             DeviceControlMode::Enter(_) => todo!(),
@@ -90,8 +208,9 @@ impl TerminalState {
         use termwiz::escape::csi::DecPrivateModeCode::*;
         match code {
             BracketedPaste => self.bracketed_paste = active,
-            EnableAlternateScreen => self.alt_screen = active,
-            ClearAndEnableAlternateScreen => self.alt_screen = active,
+            EnableAlternateScreen | OptEnableAlternateScreen | ClearAndEnableAlternateScreen => {
+                self.alt_screen = active
+            }
             ShowCursor => self.show_cursor = active,
             _ => {
                 self.dec_modes.insert(code.to_u16().unwrap(), active);
@@ -115,7 +234,12 @@ impl TerminalState {
 
     /// Handles Terminal Modes
     pub fn set_mode(&mut self, mode: TerminalMode, active: bool) {
-        info!("Set Mode {mode:?} {active}");
+        match mode {
+            TerminalMode::Code(code) => {
+                self.modes.insert(code.to_u16().unwrap(), active);
+            }
+            TerminalMode::Unspecified(code) => info!("Set Mode {code} {active}"),
+        }
     }
 
     /// Handles XtermKeyModes
@@ -1,4 +1,26 @@
 use termwiz::escape::csi::Window;
+use termwiz::escape::osc::Selection;
+
+/// Which clipboard an OSC 52 sequence is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clipboard {
+    Clipboard,
+    Primary,
+}
+
+impl Clipboard {
+    /// The clipboard an OSC 52 selection means, preferring the system clipboard
+    /// The default selection is treated as primary, cut buffers have no clipboard
+    pub fn from_selection(selection: Selection) -> Option<Clipboard> {
+        if selection.contains(Selection::CLIPBOARD) {
+            Some(Clipboard::Clipboard)
+        } else if selection.intersects(Selection::PRIMARY | Selection::SELECT) {
+            Some(Clipboard::Primary)
+        } else {
+            None
+        }
+    }
+}
 
 /// Trait for handling "window" specific ANSI commands
 /// Nothing here is needed at all, but allows for it to
@@ -9,6 +31,10 @@ pub trait WindowHandler {
     fn bell(&mut self) {}
     fn send_title(&mut self) {}
     fn steal_focus(&mut self) {}
+    /// OSC 52 write, the handler decides if it is allowed
+    fn set_clipboard(&mut self, _: Clipboard, _: String) {}
+    /// OSC 52 read, answered with Terminal::clipboard_reply if it is allowed
+    fn request_clipboard(&mut self, _: Clipboard) {}
 }
 
 impl WindowHandler for () {}